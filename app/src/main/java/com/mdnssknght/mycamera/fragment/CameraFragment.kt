@@ -4,6 +4,7 @@ import android.annotation.SuppressLint
 import android.content.Context
 import android.graphics.Bitmap
 import android.graphics.Color
+import android.graphics.ColorSpace
 import android.graphics.ImageFormat
 import android.hardware.camera2.CameraCaptureSession
 import android.hardware.camera2.CameraCharacteristics
@@ -13,6 +14,7 @@ import android.hardware.camera2.CaptureRequest
 import android.hardware.camera2.CaptureResult
 import android.hardware.camera2.DngCreator
 import android.hardware.camera2.TotalCaptureResult
+import android.hardware.camera2.params.TonemapCurve
 import android.media.Image
 import android.media.ImageReader
 import android.media.MediaScannerConnection
@@ -45,6 +47,7 @@ import com.mdnssknght.mycamera.util.getPreviewOutputSize
 import kotlinx.coroutines.Dispatchers
 import kotlinx.coroutines.launch
 import kotlinx.coroutines.suspendCancellableCoroutine
+import java.io.ByteArrayOutputStream
 import java.io.Closeable
 import java.io.File
 import java.io.FileOutputStream
@@ -249,72 +252,316 @@ class CameraFragment : Fragment() {
                             val outputBuffer: ByteBuffer
                             val width: Int
                             val height: Int
+                            val monochrome: Boolean
+
+                            // Wide gamut output, the bitmap carries the matching ICC profile into the JPEG
+                            val outputColorSpace = ColorSpace.get(ColorSpace.Named.DISPLAY_P3)
+
+                            // The OEM tone curve, reported when the capture used one, or
+                            // the preset curve it asked for
+                            val tonemapMode = result.metadata.get(CaptureResult.TONEMAP_MODE)
+                            val tonemapPresetCurve =
+                                if (tonemapMode == CaptureResult.TONEMAP_MODE_PRESET_CURVE) {
+                                    result.metadata.get(CaptureResult.TONEMAP_PRESET_CURVE)
+                                        ?: -1
+                                } else {
+                                    -1
+                                }
+                            val tonemapCurveSize = IntArray(3)
+                            var tonemapCurve = FloatArray(0)
+                            result.metadata.get(CaptureResult.TONEMAP_CURVE)?.takeIf {
+                                tonemapPresetCurve < 0
+                            }?.let { curve ->
+                                val channels = intArrayOf(
+                                    TonemapCurve.CHANNEL_RED,
+                                    TonemapCurve.CHANNEL_GREEN,
+                                    TonemapCurve.CHANNEL_BLUE
+                                )
+                                channels.forEachIndexed { index, channel ->
+                                    tonemapCurveSize[index] = curve.getPointCount(channel)
+                                }
+                                tonemapCurve = FloatArray(2 * tonemapCurveSize.sum())
+                                var offset = 0
+                                channels.forEachIndexed { index, channel ->
+                                    curve.copyColorCurve(channel, tonemapCurve, offset)
+                                    offset += 2 * tonemapCurveSize[index]
+                                }
+                            }
 
                             result.image.let { it ->
-                                width = it.planes[0].rowStride / it.planes[0].pixelStride
-                                height = it.height
+                                val rawWidth = it.planes[0].rowStride / it.planes[0].pixelStride
+                                val rawHeight = it.height
 
-                                val outputBytes = ByteArray(width * height * 4)
+                                // Only the active array is kept, the rest are margins and optical
+                                // black pixels
+                                val activeArray = characteristics.get(
+                                    CameraCharacteristics.SENSOR_INFO_ACTIVE_ARRAY_SIZE
+                                )!!
+                                width = activeArray.width()
+                                height = activeArray.height()
+
+                                // Some devices only output the active array itself
+                                val croppedBySensor = it.width == activeArray.width() &&
+                                        it.height == activeArray.height()
+                                val activeArrayRect = intArrayOf(
+                                    if (croppedBySensor) 0 else activeArray.left,
+                                    if (croppedBySensor) 0 else activeArray.top,
+                                    activeArray.width(),
+                                    activeArray.height()
+                                )
+                                // Relative to the active array, Camera2 has no default crop
+                                val cropRect = intArrayOf(0, 0, width, height)
 
                                 val colorFilterArrangement = characteristics.get(
                                     CameraCharacteristics.SENSOR_INFO_COLOR_FILTER_ARRANGEMENT
                                 )!!
 
+                                // Sensors without a color filter array are processed to grayscale
+                                monochrome = colorFilterArrangement ==
+                                        CameraCharacteristics.SENSOR_INFO_COLOR_FILTER_ARRANGEMENT_MONO ||
+                                        colorFilterArrangement ==
+                                        CameraCharacteristics.SENSOR_INFO_COLOR_FILTER_ARRANGEMENT_NIR
+
+                                // JPEG holds 8 bits per channel
+                                val bitDepth = 8
+                                val outputBytes = ByteArray(
+                                    width * height * (if (monochrome) 1 else 4) * bitDepth / 8
+                                )
+
+                                // Requested with every capture, left empty if the HAL does not provide it
+                                val lensShadingMapSize = IntArray(2)
+                                var lensShadingMap = FloatArray(0)
+                                result.metadata.get(CaptureResult.STATISTICS_LENS_SHADING_CORRECTION_MAP)
+                                    ?.let { map ->
+                                        lensShadingMapSize[0] = map.columnCount
+                                        lensShadingMapSize[1] = map.rowCount
+                                        lensShadingMap = FloatArray(map.gainFactorCount)
+                                        map.copyGainFactors(lensShadingMap, 0)
+                                    }
+
+                                // Camera2 gives the optical centre relative to the pre-correction
+                                // active array, it is moved to the active array
+                                val lensIntrinsicCalibration = FloatArray(5)
+                                val lensDistortion = FloatArray(5)
+                                (result.metadata.get(CaptureResult.LENS_INTRINSIC_CALIBRATION)
+                                    ?: characteristics.get(CameraCharacteristics.LENS_INTRINSIC_CALIBRATION))
+                                    ?.copyInto(lensIntrinsicCalibration)
+                                (result.metadata.get(CaptureResult.LENS_DISTORTION)
+                                    ?: characteristics.get(CameraCharacteristics.LENS_DISTORTION))
+                                    ?.copyInto(lensDistortion)
+                                characteristics.get(
+                                    CameraCharacteristics.SENSOR_INFO_PRE_CORRECTION_ACTIVE_ARRAY_SIZE
+                                )?.let { preCorrectionArray ->
+                                    lensIntrinsicCalibration[2] += (preCorrectionArray.left - activeArray.left).toFloat()
+                                    lensIntrinsicCalibration[3] += (preCorrectionArray.top - activeArray.top).toFloat()
+                                }
+
+                                // Monochrome sensors report neither color gains nor color
+                                // matrices, they are left empty.
                                 val colorGains = FloatArray(4)
-                                result.metadata.get(CaptureResult.COLOR_CORRECTION_GAINS)!!
-                                    .copyTo(colorGains, 0)
+                                result.metadata.get(CaptureResult.COLOR_CORRECTION_GAINS)
+                                    ?.copyTo(colorGains, 0)
+
+                                // Manual white balance in kelvin and tint, zero keeps the capture's gains
+                                val whiteBalanceTemperature = 0f
+                                val whiteBalanceTint = 0f
 
                                 val whiteLevel =
                                     characteristics.get(CameraCharacteristics.SENSOR_INFO_WHITE_LEVEL)!!
 
-                                val blackLevel = IntArray(4)
+                                val blackLevelPattern = IntArray(4)
                                 characteristics.get(CameraCharacteristics.SENSOR_BLACK_LEVEL_PATTERN)!!
-                                    .copyTo(blackLevel, 0)
-
+                                    .copyTo(blackLevelPattern, 0)
+                                val blackLevel = FloatArray(4) { index -> blackLevelPattern[index].toFloat() }
+
+                                // Levels of this very frame, preferred over the static ones when the HAL reports them
+                                val dynamicWhiteLevel =
+                                    result.metadata.get(CaptureResult.SENSOR_DYNAMIC_WHITE_LEVEL) ?: 0
+                                val dynamicBlackLevel =
+                                    result.metadata.get(CaptureResult.SENSOR_DYNAMIC_BLACK_LEVEL)
+
+                                // Relative to the raw buffer, which only matches when the sensor keeps its margins
+                                val opticalBlackRegions = if (croppedBySensor) {
+                                    IntArray(0)
+                                } else {
+                                    characteristics.get(CameraCharacteristics.SENSOR_OPTICAL_BLACK_REGIONS)
+                                        ?.flatMap { region ->
+                                            listOf(region.left, region.top, region.width(), region.height())
+                                        }
+                                        ?.toIntArray() ?: IntArray(0)
+                                }
+                                // Only needed when the HAL reports no per-frame black level
+                                val estimateBlackLevel = dynamicBlackLevel == null && opticalBlackRegions.isNotEmpty()
+
+                                val colorMatrix1 = FloatArray(9)
+                                val colorMatrix2 = FloatArray(9)
+                                // Devices without a per-unit calibration get identity
+                                val cameraCalibration1 = floatArrayOf(1f, 0f, 0f, 0f, 1f, 0f, 0f, 0f, 1f)
+                                val cameraCalibration2 = cameraCalibration1.copyOf()
                                 val forwardMatrix1 = FloatArray(9)
                                 val forwardMatrix2 = FloatArray(9)
 
                                 val rationalDestination = arrayOfNulls<Rational>(9)
 
-                                characteristics.get(CameraCharacteristics.SENSOR_FORWARD_MATRIX1)!!
-                                    .copyElements(rationalDestination, 0)
-                                rationalDestination.forEachIndexed { index, rational ->
-                                    forwardMatrix1[index] = rational!!.toFloat()
-                                }
-
-                                characteristics.get(CameraCharacteristics.SENSOR_FORWARD_MATRIX2)!!
-                                    .copyElements(rationalDestination, 0)
-                                rationalDestination.forEachIndexed { index, rational ->
-                                    forwardMatrix2[index] = rational!!.toFloat()
-                                }
+                                characteristics.get(CameraCharacteristics.SENSOR_COLOR_TRANSFORM1)
+                                    ?.let { matrix ->
+                                        matrix.copyElements(rationalDestination, 0)
+                                        rationalDestination.forEachIndexed { index, rational ->
+                                            colorMatrix1[index] = rational!!.toFloat()
+                                        }
+                                    }
+
+                                characteristics.get(CameraCharacteristics.SENSOR_COLOR_TRANSFORM2)
+                                    ?.let { matrix ->
+                                        matrix.copyElements(rationalDestination, 0)
+                                        rationalDestination.forEachIndexed { index, rational ->
+                                            colorMatrix2[index] = rational!!.toFloat()
+                                        }
+                                    }
+
+                                characteristics.get(CameraCharacteristics.SENSOR_CALIBRATION_TRANSFORM1)
+                                    ?.let { matrix ->
+                                        matrix.copyElements(rationalDestination, 0)
+                                        rationalDestination.forEachIndexed { index, rational ->
+                                            cameraCalibration1[index] = rational!!.toFloat()
+                                        }
+                                    }
+
+                                characteristics.get(CameraCharacteristics.SENSOR_CALIBRATION_TRANSFORM2)
+                                    ?.let { matrix ->
+                                        matrix.copyElements(rationalDestination, 0)
+                                        rationalDestination.forEachIndexed { index, rational ->
+                                            cameraCalibration2[index] = rational!!.toFloat()
+                                        }
+                                    }
+
+                                characteristics.get(CameraCharacteristics.SENSOR_FORWARD_MATRIX1)
+                                    ?.let { matrix ->
+                                        matrix.copyElements(rationalDestination, 0)
+                                        rationalDestination.forEachIndexed { index, rational ->
+                                            forwardMatrix1[index] = rational!!.toFloat()
+                                        }
+                                    }
+
+                                characteristics.get(CameraCharacteristics.SENSOR_FORWARD_MATRIX2)
+                                    ?.let { matrix ->
+                                        matrix.copyElements(rationalDestination, 0)
+                                        rationalDestination.forEachIndexed { index, rational ->
+                                            forwardMatrix2[index] = rational!!.toFloat()
+                                        }
+                                    }
+
+                                // EXIF LightSource values, zero when unknown
+                                val calibrationIlluminant1 = characteristics.get(
+                                    CameraCharacteristics.SENSOR_REFERENCE_ILLUMINANT1
+                                ) ?: 0
+                                val calibrationIlluminant2 = characteristics.get(
+                                    CameraCharacteristics.SENSOR_REFERENCE_ILLUMINANT2
+                                )?.toInt() ?: 0
+
+                                // The capture is already exposed by the camera's own auto exposure
+                                val exposureBias = 0f
+                                val autoExposure = false
+
+                                // No creative look by default
+                                val look: String? = null
+
+                                val estimatedGains = FloatArray(4)
+                                val estimatedExposure = FloatArray(1)
 
                                 RawProcessor.process(
-                                    width,
-                                    height,
+                                    rawWidth,
+                                    rawHeight,
                                     it.planes[0].buffer,
                                     outputBytes,
+                                    activeArrayRect,
+                                    cropRect,
                                     colorFilterArrangement,
                                     whiteLevel,
                                     blackLevel,
+                                    dynamicWhiteLevel,
+                                    dynamicBlackLevel,
+                                    opticalBlackRegions,
+                                    estimateBlackLevel,
+                                    lensShadingMapSize,
+                                    lensShadingMap,
+                                    lensIntrinsicCalibration,
+                                    lensDistortion,
                                     colorGains,
+                                    whiteBalanceTemperature,
+                                    whiteBalanceTint,
+                                    colorMatrix1,
+                                    colorMatrix2,
+                                    cameraCalibration1,
+                                    cameraCalibration2,
                                     forwardMatrix1,
-                                    forwardMatrix2
+                                    forwardMatrix2,
+                                    calibrationIlluminant1,
+                                    calibrationIlluminant2,
+                                    exposureBias,
+                                    autoExposure,
+                                    look,
+                                    tonemapCurveSize,
+                                    tonemapCurve,
+                                    tonemapPresetCurve,
+                                    outputColorSpace,
+                                    bitDepth,
+                                    estimatedGains,
+                                    estimatedExposure
                                 )
 
+                                // Missing color gains are estimated by the native side
+                                if (colorGains.all { gain -> gain == 0f }) {
+                                    Log.d(TAG, "Auto white balance gains: ${estimatedGains.contentToString()}")
+                                }
+
+                                if (autoExposure) {
+                                    Log.d(TAG, "Auto exposure bias: ${estimatedExposure[0]} EV")
+                                }
+
                                 outputBuffer = ByteBuffer.wrap(outputBytes)
                             }
 
                             // Hacky, I know
                             try {
-                                val bitmap = createBitmap(width, height)
-                                    .apply { copyPixelsFromBuffer(outputBuffer) }
+                                val bitmap = createBitmap(
+                                    width,
+                                    height,
+                                    Bitmap.Config.ARGB_8888,
+                                    true,
+                                    outputColorSpace
+                                ).apply {
+                                    if (monochrome) {
+                                        val pixels = IntArray(width * height) { index ->
+                                            val value = outputBuffer.get(index).toInt() and 0xff
+                                            Color.rgb(value, value, value)
+                                        }
+                                        setPixels(pixels, 0, width, 0, 0, width, height)
+                                    } else {
+                                        copyPixelsFromBuffer(outputBuffer)
+                                    }
+                                }
 
-                                val file = createFile("jpg")
-                                FileOutputStream(file).use { it ->
+                                val jpeg = ByteArrayOutputStream().use {
                                     bitmap.compress(
                                         Bitmap.CompressFormat.JPEG,
                                         100, it
                                     )
+                                    it.toByteArray()
+                                }
+
+                                // Tags the JPEG with a profile matching the pipeline's output
+                                val file = createFile("jpg")
+                                FileOutputStream(file).use {
+                                    it.write(
+                                        RawProcessor.embedIccProfile(
+                                            jpeg,
+                                            tonemapCurveSize,
+                                            tonemapCurve,
+                                            tonemapPresetCurve,
+                                            outputColorSpace
+                                        )
+                                    )
                                 }
 
                                 ExifInterface(file.absolutePath).let { exif ->
@@ -450,7 +697,14 @@ class CameraFragment : Fragment() {
 
         val captureRequest =
             session.device.createCaptureRequest(CameraDevice.TEMPLATE_STILL_CAPTURE)
-                .apply { addTarget(imageReader.surface) }
+                .apply {
+                    addTarget(imageReader.surface)
+                    // The RAW image is not shading corrected, the native side applies the map
+                    set(
+                        CaptureRequest.STATISTICS_LENS_SHADING_MAP_MODE,
+                        CaptureRequest.STATISTICS_LENS_SHADING_MAP_MODE_ON
+                    )
+                }
 
         session.capture(captureRequest.build(), object : CameraCaptureSession.CaptureCallback() {
 
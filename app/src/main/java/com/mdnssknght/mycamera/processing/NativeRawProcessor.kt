@@ -19,12 +19,68 @@ class NativeRawProcessor {
             height: Int,
             data: ByteBuffer,
             out: ByteArray,
+            activeArray: IntArray,
+            crop: IntArray,
             colorFilterArrangement: Int,
             whiteLevel: Int,
-            blackLevel: IntArray,
+            blackLevel: FloatArray,
+            // Reported for this frame, null or zero when missing
+            dynamicWhiteLevel: Int,
+            dynamicBlackLevel: FloatArray?,
+            // Flattened [left, top, width, height] rectangles
+            opticalBlackRegions: IntArray,
+            estimateBlackLevel: Boolean,
+            // Columns and rows, zero when there is no map
+            lensShadingMapSize: IntArray,
+            // Four gains per grid point, in the layout of LensShadingMap.copyGainFactors
+            lensShadingMap: FloatArray,
+            // [f_x, f_y, c_x, c_y, s] relative to the active array and [k1, k2, k3, k4, k5],
+            // zero when missing
+            lensIntrinsicCalibration: FloatArray,
+            lensDistortion: FloatArray,
             colorGains: FloatArray,
+            // Kelvin and tint of a manual white balance, a temperature of zero keeps colorGains
+            whiteBalanceTemperature: Float,
+            whiteBalanceTint: Float,
+            colorMatrix1: FloatArray,
+            colorMatrix2: FloatArray,
+            cameraCalibration1: FloatArray,
+            cameraCalibration2: FloatArray,
             forwardMatrix1: FloatArray,
             forwardMatrix2: FloatArray,
+            calibrationIlluminant1: Int,
+            calibrationIlluminant2: Int,
+            // Bias in EV, replaced by automatic exposure when enabled
+            exposureBias: Float,
+            autoExposure: Boolean,
+            // Contents of a .cube file applied to the encoded image, none when null
+            look: String?,
+            // Point counts of red, green and blue, zero when there is no curve, and their
+            // [input, output] pairs one channel after the other, in place of the transfer
+            // function
+            tonemapCurveSize: IntArray,
+            tonemapCurve: FloatArray,
+            // CaptureResult.TONEMAP_PRESET_CURVE in place of the transfer function, -1 when the
+            // capture used none
+            tonemapPresetCurve: Int,
+            // Id of an android.graphics.ColorSpace.Named
+            colorSpace: Int,
+            // Bits per output channel, 8 or 16
+            bitDepth: Int,
+            // Filled with the auto white balance gains when they were estimated
+            estimatedGains: FloatArray,
+            // Filled with the bias in EV picked by automatic exposure
+            estimatedExposure: FloatArray,
         )
+
+        // Returns the JPEG with an ICC profile of the ColorSpace.Named id embedded, with the
+        // curves nativeProcess encoded the image with given the same tone curve arguments
+        external fun nativeEmbedIccProfile(
+            jpeg: ByteArray,
+            tonemapCurveSize: IntArray,
+            tonemapCurve: FloatArray,
+            tonemapPresetCurve: Int,
+            colorSpace: Int
+        ): ByteArray
     }
 }
\ No newline at end of file
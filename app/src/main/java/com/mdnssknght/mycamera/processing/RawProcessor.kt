@@ -1,5 +1,6 @@
 package com.mdnssknght.mycamera.processing
 
+import android.graphics.ColorSpace
 import java.nio.ByteBuffer
 
 object RawProcessor {
@@ -23,12 +24,40 @@ object RawProcessor {
         height: Int,
         data: ByteBuffer,
         out: ByteArray,
+        activeArray: IntArray,
+        crop: IntArray,
         colorFilterArrangement: Int,
         whiteLevel: Int,
-        blackLevel: IntArray,
+        blackLevel: FloatArray,
+        dynamicWhiteLevel: Int,
+        dynamicBlackLevel: FloatArray?,
+        opticalBlackRegions: IntArray,
+        estimateBlackLevel: Boolean,
+        lensShadingMapSize: IntArray,
+        lensShadingMap: FloatArray,
+        lensIntrinsicCalibration: FloatArray,
+        lensDistortion: FloatArray,
         colorGains: FloatArray,
+        whiteBalanceTemperature: Float,
+        whiteBalanceTint: Float,
+        colorMatrix1: FloatArray,
+        colorMatrix2: FloatArray,
+        cameraCalibration1: FloatArray,
+        cameraCalibration2: FloatArray,
         forwardMatrix1: FloatArray,
         forwardMatrix2: FloatArray,
+        calibrationIlluminant1: Int,
+        calibrationIlluminant2: Int,
+        exposureBias: Float,
+        autoExposure: Boolean,
+        look: String?,
+        tonemapCurveSize: IntArray,
+        tonemapCurve: FloatArray,
+        tonemapPresetCurve: Int,
+        colorSpace: ColorSpace,
+        bitDepth: Int,
+        estimatedGains: FloatArray,
+        estimatedExposure: FloatArray,
     ) {
         NativeRawProcessor.nativeProcess(
             pointerHandle,
@@ -36,12 +65,56 @@ object RawProcessor {
             height,
             data,
             out,
+            activeArray,
+            crop,
             colorFilterArrangement,
             whiteLevel,
             blackLevel,
+            dynamicWhiteLevel,
+            dynamicBlackLevel,
+            opticalBlackRegions,
+            estimateBlackLevel,
+            lensShadingMapSize,
+            lensShadingMap,
+            lensIntrinsicCalibration,
+            lensDistortion,
             colorGains,
+            whiteBalanceTemperature,
+            whiteBalanceTint,
+            colorMatrix1,
+            colorMatrix2,
+            cameraCalibration1,
+            cameraCalibration2,
             forwardMatrix1,
-            forwardMatrix2
+            forwardMatrix2,
+            calibrationIlluminant1,
+            calibrationIlluminant2,
+            exposureBias,
+            autoExposure,
+            look,
+            tonemapCurveSize,
+            tonemapCurve,
+            tonemapPresetCurve,
+            colorSpace.id,
+            bitDepth,
+            estimatedGains,
+            estimatedExposure
+        )
+    }
+
+    fun embedIccProfile(
+        jpeg: ByteArray,
+        tonemapCurveSize: IntArray,
+        tonemapCurve: FloatArray,
+        tonemapPresetCurve: Int,
+        colorSpace: ColorSpace
+    ): ByteArray {
+        return NativeRawProcessor.nativeEmbedIccProfile(
+            jpeg,
+            tonemapCurveSize,
+            tonemapCurve,
+            tonemapPresetCurve,
+            colorSpace.id
         )
     }
 }
\ No newline at end of file
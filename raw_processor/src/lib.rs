@@ -3,13 +3,13 @@ use std::{panic, slice};
 use android_logger::Config;
 use jni::{
     JNIEnv,
-    objects::{JByteArray, JByteBuffer, JClass, JFloatArray, JIntArray},
-    sys::{jbyte, jint, jlong},
+    objects::{JByteArray, JByteBuffer, JClass, JFloatArray, JIntArray, JString},
+    sys::{jboolean, jbyte, jbyteArray, jfloat, jint, jlong},
 };
 use log::{LevelFilter, error, info};
 use vulkano::VulkanLibrary;
 
-mod pipeline;
+pub mod pipeline;
 
 #[unsafe(no_mangle)]
 pub extern "system" fn Java_com_mdnssknght_mycamera_processing_NativeRawProcessor_00024Companion_nativeInit(
@@ -65,28 +65,150 @@ pub extern "system" fn Java_com_mdnssknght_mycamera_processing_NativeRawProcesso
 
 #[unsafe(no_mangle)]
 pub extern "system" fn Java_com_mdnssknght_mycamera_processing_NativeRawProcessor_00024Companion_nativeProcess(
-    env: JNIEnv,
+    mut env: JNIEnv,
     _: JClass,
     handle: jlong,
     width: jint,
     height: jint,
     data: JByteBuffer,
     out: JByteArray,
+    active_array: JIntArray,
+    crop: JIntArray,
     color_filter_arrangement: jint,
     white_level: jint,
-    black_level: JIntArray,
+    black_level: JFloatArray,
+    dynamic_white_level: jint,
+    dynamic_black_level: JFloatArray,
+    optical_black_regions: JIntArray,
+    estimate_black_level: jboolean,
+    lens_shading_map_size: JIntArray,
+    lens_shading_map: JFloatArray,
+    lens_intrinsic_calibration: JFloatArray,
+    lens_distortion: JFloatArray,
     color_gains: JFloatArray,
+    white_balance_temperature: jfloat,
+    white_balance_tint: jfloat,
+    color_matrix_1: JFloatArray,
+    color_matrix_2: JFloatArray,
+    camera_calibration_1: JFloatArray,
+    camera_calibration_2: JFloatArray,
     forward_matrix_1: JFloatArray,
     forward_matrix_2: JFloatArray,
+    calibration_illuminant_1: jint,
+    calibration_illuminant_2: jint,
+    exposure_bias: jfloat,
+    auto_exposure: jboolean,
+    look: JString,
+    tonemap_curve_size: JIntArray,
+    tonemap_curve: JFloatArray,
+    tonemap_preset_curve: jint,
+    color_space: jint,
+    bit_depth: jint,
+    estimated_gains: JFloatArray,
+    estimated_exposure: JFloatArray,
 ) {
     let context = unsafe { &*(handle as *const pipeline::Context) };
 
-    let black_level = {
+    let active_array = {
+        let mut data = [0i32; 4];
+        env.get_int_array_region(active_array, 0, &mut data)
+            .unwrap();
+        let [x, y, width, height] = data.map(|n| n as u32);
+        pipeline::Rect::new(x, y, width, height)
+    };
+
+    let crop = {
         let mut data = [0i32; 4];
-        env.get_int_array_region(black_level, 0, &mut data).unwrap();
+        env.get_int_array_region(crop, 0, &mut data).unwrap();
+        let [x, y, width, height] = data.map(|n| n as u32);
+        pipeline::Rect::new(x, y, width, height)
+    };
+
+    let black_level = {
+        let mut data = [0f32; 4];
+        env.get_float_array_region(black_level, 0, &mut data)
+            .unwrap();
         data
     };
 
+    // Per-frame levels are optional, a null array or a zero white level when missing
+    let dynamic_white_level = (dynamic_white_level > 0).then_some(dynamic_white_level);
+
+    let dynamic_black_level = (!dynamic_black_level.is_null()).then(|| {
+        let mut data = [0f32; 4];
+        env.get_float_array_region(&dynamic_black_level, 0, &mut data)
+            .unwrap();
+        data
+    });
+
+    // Flattened rectangles, four values each
+    let optical_black_regions = {
+        let len = env.get_array_length(&optical_black_regions).unwrap();
+        let mut data = vec![0i32; len as usize];
+        env.get_int_array_region(optical_black_regions, 0, &mut data)
+            .unwrap();
+        data.chunks_exact(4)
+            .map(|rect| {
+                let [x, y, width, height] = [rect[0], rect[1], rect[2], rect[3]].map(|n| n as u32);
+                pipeline::Rect::new(x, y, width, height)
+            })
+            .collect()
+    };
+
+    // A map of zero columns and rows means there is none
+    let lens_shading_map = {
+        let mut size = [0i32; 2];
+        env.get_int_array_region(lens_shading_map_size, 0, &mut size)
+            .unwrap();
+        let [columns, rows] = size.map(|n| n as u32);
+
+        (columns * rows > 0).then(|| {
+            let mut data = vec![0f32; (columns * rows * 4) as usize];
+            env.get_float_array_region(lens_shading_map, 0, &mut data)
+                .unwrap();
+            let gains = data
+                .chunks_exact(4)
+                .map(|gains| [gains[0], gains[1], gains[2], gains[3]])
+                .collect();
+            pipeline::GainMap::new([columns, rows], gains)
+        })
+    };
+
+    // Missing calibrations are left zeroed by the caller
+    let lens_distortion = {
+        let mut intrinsics = [0f32; 5];
+        env.get_float_array_region(lens_intrinsic_calibration, 0, &mut intrinsics)
+            .unwrap();
+        let mut distortion = [0f32; 5];
+        env.get_float_array_region(lens_distortion, 0, &mut distortion)
+            .unwrap();
+
+        (intrinsics[0] > 0.0 && intrinsics[1] > 0.0 && distortion.iter().any(|&n| n != 0.0))
+            .then(|| pipeline::LensDistortion::from_camera2(intrinsics, distortion))
+    };
+
+    // Applied with the interpolation and in the space most .cube files are made for. A file
+    // that does not parse is left out rather than failing the capture.
+    let looks = if look.is_null() {
+        vec![]
+    } else {
+        let text: String = env.get_string(&look).unwrap().into();
+
+        match pipeline::Lut::from_cube(&text) {
+            Ok(lut) => vec![pipeline::Look {
+                lut,
+                interpolation: pipeline::LutInterpolation::Tetrahedral,
+                space: pipeline::LutSpace::Encoded,
+            }],
+            Err(e) => {
+                error!("Ignoring look: {e}");
+                vec![]
+            }
+        }
+    };
+
+    let tone_curve = tone_curve(&env, tonemap_curve_size, tonemap_curve);
+
     let color_gains = {
         let mut data = [0f32; 4];
         env.get_float_array_region(color_gains, 0, &mut data)
@@ -94,46 +216,214 @@ pub extern "system" fn Java_com_mdnssknght_mycamera_processing_NativeRawProcesso
         data
     };
 
+    let color_matrix_1 = {
+        let mut data = [0f32; 9];
+        env.get_float_array_region(color_matrix_1, 0, &mut data)
+            .unwrap();
+        data
+    };
+
+    let color_matrix_2 = {
+        let mut data = [0f32; 9];
+        env.get_float_array_region(color_matrix_2, 0, &mut data)
+            .unwrap();
+        data
+    };
+
+    let camera_calibration_1 = {
+        let mut data = [0f32; 9];
+        env.get_float_array_region(camera_calibration_1, 0, &mut data)
+            .unwrap();
+        data
+    };
+
+    let camera_calibration_2 = {
+        let mut data = [0f32; 9];
+        env.get_float_array_region(camera_calibration_2, 0, &mut data)
+            .unwrap();
+        data
+    };
+
+    // Missing forward matrices are left zeroed by the caller
     let forward_matrix_1 = {
         let mut data = [0f32; 9];
         env.get_float_array_region(forward_matrix_1, 0, &mut data)
             .unwrap();
-        data
+        Some(data).filter(|matrix| matrix.iter().any(|&n| n != 0.0))
     };
 
     let forward_matrix_2 = {
         let mut data = [0f32; 9];
         env.get_float_array_region(forward_matrix_2, 0, &mut data)
             .unwrap();
-        data
+        Some(data).filter(|matrix| matrix.iter().any(|&n| n != 0.0))
     };
 
-    let mut finish = pipeline::Finish::new();
-
-    finish.finish(
-        &context,
-        env.get_direct_buffer_address(&data).unwrap(),
-        env.get_direct_buffer_capacity(&data).unwrap(),
-        [width, height],
-        color_filter_arrangement,
+    let parameters = pipeline::Parameters {
+        size: [width as u32, height as u32],
+        active_area: active_array,
+        crop,
+        cfa_pattern: pipeline::CfaPattern::from_color_filter_arrangement(color_filter_arrangement),
         white_level,
         black_level,
+        dynamic_white_level,
+        dynamic_black_level,
+        optical_black_regions,
+        estimate_black_level: estimate_black_level != 0,
+        lens_shading_map,
+        // Camera2 reports no defect list. Detection on the image stays off, as a fixed threshold
+        // mistakes the noise of high sensitivities and fine detail for defects.
+        bad_pixels: vec![],
+        bad_rects: vec![],
+        defect_threshold: None,
         color_gains,
+        // A temperature overrides the capture's gains, which are estimated when missing
+        white_balance: if white_balance_temperature > 0.0 {
+            pipeline::WhiteBalance::Manual {
+                temperature: white_balance_temperature,
+                tint: white_balance_tint,
+            }
+        } else if color_gains.iter().all(|&n| n == 0.0) {
+            pipeline::WhiteBalance::Auto(pipeline::AwbMethod::GreyWorld)
+        } else {
+            pipeline::WhiteBalance::AsShot
+        },
+        highlight_mode: pipeline::HighlightMode::Reconstruct,
+        highlight_mask: false,
+        chromatic_aberration: Some(pipeline::ChromaticAberrationCorrection::Auto),
+        color_matrix_1,
+        color_matrix_2,
+        camera_calibration_1,
+        camera_calibration_2,
+        // Camera2 has no analog balance
+        analog_balance: [1.0; 3],
         forward_matrix_1,
         forward_matrix_2,
-    );
+        calibration_illuminant_1,
+        calibration_illuminant_2,
+        chromatic_adaptation: pipeline::ChromaticAdaptation::Bradford,
+        lens_distortion,
+        distortion_crop: pipeline::DistortionCrop::LargestValid,
+        resampling: pipeline::Resampling::Lanczos,
+        gamut_compression: Some(pipeline::GamutCompression::ACES),
+        // Automatic exposure brings the median to mid grey
+        exposure: if auto_exposure != 0 {
+            pipeline::Exposure::Auto {
+                percentile: 0.5,
+                target: 0.18,
+            }
+        } else {
+            pipeline::Exposure::Manual(exposure_bias)
+        },
+        // The compression of HDR+, left out under an OEM curve, which already carries the
+        // camera's own tone mapping
+        local_tone_mapping: tone_curve.is_none().then_some(3.8),
+        tone_mapping: tone_curve
+            .is_none()
+            .then_some(pipeline::ToneMapping::AcesFitted),
+        color_adjustment: None,
+        looks,
+        color_space: pipeline::ColorSpace::from_android_id(color_space),
+        transfer_function: preset_transfer_function(tonemap_preset_curve),
+        tone_curve,
+        bit_depth: if bit_depth == 16 {
+            pipeline::BitDepth::Sixteen
+        } else {
+            pipeline::BitDepth::Eight
+        },
+    };
 
-    let output_buffer = match finish.get_buffer_output() {
-        Some(buffer_guard) => {
-            let buffer = buffer_guard
-                .read()
-                .expect("Failed to lock buffer for reading");
-            unsafe { slice::from_raw_parts(buffer.as_ptr() as *const jbyte, buffer.len()) }
-        }
-        _ => panic!("Something went wrong"),
+    let mut finish = pipeline::Finish::new();
+
+    // The direct buffer stays valid while the call holds a reference to it
+    let raw = unsafe {
+        slice::from_raw_parts(
+            env.get_direct_buffer_address(&data).unwrap(),
+            env.get_direct_buffer_capacity(&data).unwrap(),
+        )
     };
 
-    env.set_byte_array_region(out, 0, output_buffer).unwrap();
+    finish.finish(&context, raw, &parameters);
+
+    let output = finish.get_output().expect("Something went wrong");
+    let output_bytes =
+        unsafe { slice::from_raw_parts(output.as_ptr() as *const jbyte, output.len()) };
+
+    env.set_byte_array_region(out, 0, output_bytes).unwrap();
+
+    if let Some(gains) = finish.get_estimated_gains() {
+        env.set_float_array_region(estimated_gains, 0, &gains)
+            .unwrap();
+    }
+
+    if let Some(exposure) = finish.get_estimated_exposure() {
+        env.set_float_array_region(estimated_exposure, 0, &[exposure])
+            .unwrap();
+    }
 
     info!("Command buffer execution succeeded");
 }
+
+#[unsafe(no_mangle)]
+pub extern "system" fn Java_com_mdnssknght_mycamera_processing_NativeRawProcessor_00024Companion_nativeEmbedIccProfile(
+    env: JNIEnv,
+    _: JClass,
+    jpeg: JByteArray,
+    tonemap_curve_size: JIntArray,
+    tonemap_curve: JFloatArray,
+    tonemap_preset_curve: jint,
+    color_space: jint,
+) -> jbyteArray {
+    let jpeg = env.convert_byte_array(&jpeg).unwrap();
+
+    // The curves the pixels were encoded with, as nativeProcess picks them
+    let color_space = pipeline::ColorSpace::from_android_id(color_space);
+    let transfer_function =
+        preset_transfer_function(tonemap_preset_curve).unwrap_or(color_space.transfer_function());
+    let tone_curve = tone_curve(&env, tonemap_curve_size, tonemap_curve);
+    let profile = pipeline::icc_profile(&color_space, &transfer_function, tone_curve.as_ref());
+
+    // Left untagged rather than failing the capture
+    let output = pipeline::embed_in_jpeg(&jpeg, &profile).unwrap_or_else(|e| {
+        error!("Leaving the JPEG without a profile: {e}");
+        jpeg.clone()
+    });
+
+    env.byte_array_from_slice(&output).unwrap().into_raw()
+}
+
+// Point counts of red, green and blue, zero when the capture reports no curve, and their points
+// one channel after the other
+fn tone_curve(
+    env: &JNIEnv,
+    tonemap_curve_size: JIntArray,
+    tonemap_curve: JFloatArray,
+) -> Option<pipeline::ToneCurve> {
+    let mut size = [0i32; 3];
+    env.get_int_array_region(tonemap_curve_size, 0, &mut size)
+        .unwrap();
+    let size = size.map(|n| n.max(0) as usize);
+
+    // Longer curves than the shader takes are resampled
+    if size.iter().any(|&n| n > pipeline::MAX_TONE_CURVE_POINTS) {
+        info!("Resampling a tone curve of {size:?} points");
+    }
+
+    size.iter().all(|&n| n >= 2).then(|| {
+        let mut data = vec![0f32; 2 * size.iter().sum::<usize>()];
+        env.get_float_array_region(tonemap_curve, 0, &mut data)
+            .unwrap();
+        let mut points = data.chunks_exact(2).map(|point| [point[0], point[1]]);
+        let [red, green, blue] = size.map(|n| points.by_ref().take(n).collect());
+        pipeline::ToneCurve::new(red, green, blue)
+    })
+}
+
+// Camera2's TONEMAP_PRESET_CURVE, negative when the capture used none
+fn preset_transfer_function(tonemap_preset_curve: jint) -> Option<pipeline::TransferFunction> {
+    match tonemap_preset_curve {
+        0 /* SRGB */ => Some(pipeline::TransferFunction::SRGB),
+        1 /* REC709 */ => Some(pipeline::TransferFunction::REC709),
+        _ => None,
+    }
+}
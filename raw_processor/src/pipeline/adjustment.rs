@@ -0,0 +1,36 @@
+// Creative colour adjustments in Björn Ottosson's OKLab, see shaders/finishing_22.slang
+
+use crate::pipeline::color::{ChromaticAdaptation, ColorSpace, D65, Matrix3};
+
+/// XYZ relative to D65 to the cone responses of OKLab, before their cube root.
+const XYZ_TO_LMS: Matrix3 = Matrix3([
+    [0.8189330101, 0.3618667424, -0.1288597137],
+    [0.0329845436, 0.9293118715, 0.0361456387],
+    [0.0482003018, 0.2643662691, 0.6338517070],
+]);
+
+/// Adjustments on linear output RGB, after tone mapping. Their neutral values are one for the
+/// saturation, contrast and white point and zero for the vibrance and black point.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ColorAdjustment {
+    /// Scale of the chroma
+    pub saturation: f32,
+    /// Raises the chroma of muted colours more than of saturated ones and skin tones, lowers
+    /// it when negative
+    pub vibrance: f32,
+    /// Exponent of the lightness around the pivot
+    pub contrast: f32,
+    /// Linear luminance that contrast leaves unchanged, e.g. 0.18
+    pub pivot: f32,
+    /// Linear values mapped to zero and one
+    pub black: f32,
+    pub white: f32,
+}
+
+/// Linear RGB of the colour space to the cone responses of OKLab, adapting its white point to
+/// D65 first.
+pub fn rgb_to_lms(color_space: &ColorSpace) -> Matrix3 {
+    XYZ_TO_LMS
+        * ChromaticAdaptation::Bradford.matrix(color_space.white(), D65)
+        * color_space.to_xyz()
+}
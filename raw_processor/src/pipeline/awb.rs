@@ -0,0 +1,229 @@
+// Auto white balance from statistics gathered on the raw image, see shaders/finishing_9.slang
+
+/// Bins of the per channel histogram, over normalized values from zero to one.
+pub const HISTOGRAM_BINS: usize = 256;
+
+/// Assumption used to estimate the scene illuminant.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AwbMethod {
+    /// The scene averages to grey
+    GreyWorld,
+    /// The brightest unclipped value of each channel is white
+    WhitePatch,
+    /// The scene's edges average to grey
+    GreyEdge,
+    /// Like white patch with the given fraction of each channel (e.g. 0.99) instead of its
+    /// maximum, so a few bright outliers do not decide the result
+    Percentile(f32),
+}
+
+/// Where the white balance gains come from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WhiteBalance {
+    /// The colour gains reported with the capture
+    AsShot,
+    /// The colour gains reported with the capture, the estimate is only reported back
+    Estimate(AwbMethod),
+    /// The estimate replaces the colour gains
+    Auto(AwbMethod),
+    /// Gains for a correlated colour temperature in kelvin and a tint, found through the
+    /// colour matrices
+    Manual { temperature: f32, tint: f32 },
+}
+
+impl WhiteBalance {
+    pub fn awb_method(&self) -> Option<AwbMethod> {
+        match self {
+            WhiteBalance::AsShot | WhiteBalance::Manual { .. } => None,
+            WhiteBalance::Estimate(method) | WhiteBalance::Auto(method) => Some(*method),
+        }
+    }
+}
+
+/// Statistics of each colour, red, green and blue, with both greens merged.
+pub struct AwbStatistics {
+    sum: [f64; 3],
+    gradient: [f64; 3],
+    count: [f64; 3],
+    histogram: [[u64; HISTOGRAM_BINS]; 3],
+}
+
+impl AwbStatistics {
+    /// Adds up the per workgroup sums and the histogram, both in CFA channel order.
+    pub fn new(sums: &[f32], histogram: &[u32]) -> AwbStatistics {
+        // CFA channel to colour
+        const COLORS: [usize; 4] = [0, 1, 1, 2];
+
+        let mut statistics = AwbStatistics {
+            sum: [0.0; 3],
+            gradient: [0.0; 3],
+            count: [0.0; 3],
+            histogram: [[0; HISTOGRAM_BINS]; 3],
+        };
+
+        // Sum, gradient sum and count of each channel
+        for slot in sums.chunks_exact(12) {
+            for (channel, &color) in COLORS.iter().enumerate() {
+                statistics.sum[color] += slot[channel] as f64;
+                statistics.gradient[color] += slot[4 + channel] as f64;
+                statistics.count[color] += slot[8 + channel] as f64;
+            }
+        }
+
+        for (channel, bins) in histogram.chunks_exact(HISTOGRAM_BINS).enumerate() {
+            for (bin, &n) in bins.iter().enumerate() {
+                statistics.histogram[COLORS[channel]][bin] += n as u64;
+            }
+        }
+
+        statistics
+    }
+
+    // Value below which the given fraction of a colour lies
+    fn percentile(&self, color: usize, fraction: f64) -> f64 {
+        let histogram = &self.histogram[color];
+        let total: u64 = histogram.iter().sum();
+        let target = (total as f64 * fraction.clamp(0.0, 1.0)).ceil() as u64;
+
+        let mut accumulated = 0;
+        for (bin, &n) in histogram.iter().enumerate() {
+            accumulated += n;
+            if n > 0 && accumulated >= target {
+                return (bin + 1) as f64 / HISTOGRAM_BINS as f64;
+            }
+        }
+
+        0.0
+    }
+
+    /// Colour gains that make the estimated illuminant neutral, in the order of
+    /// `COLOR_CORRECTION_GAINS` with green at one. Nothing when a colour has no usable sites.
+    pub fn gains(&self, method: AwbMethod) -> Option<[f32; 4]> {
+        let illuminant = [0, 1, 2].map(|color| {
+            if self.count[color] == 0.0 {
+                return 0.0;
+            }
+
+            match method {
+                AwbMethod::GreyWorld => self.sum[color] / self.count[color],
+                AwbMethod::WhitePatch => self.percentile(color, 1.0),
+                AwbMethod::GreyEdge => self.gradient[color] / self.count[color],
+                AwbMethod::Percentile(fraction) => self.percentile(color, fraction as f64),
+            }
+        });
+
+        if illuminant.iter().any(|&n| n <= 0.0) {
+            return None;
+        }
+
+        let [red, green, blue] = illuminant;
+        Some([(green / red) as f32, 1.0, 1.0, (green / blue) as f32])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Per workgroup sums as finishing_9 writes them, from the mean value and mean gradient of
+    // each colour over the given number of sites per CFA channel
+    fn sums(workgroups: usize, mean: [f32; 3], gradient: [f32; 3], count: f32) -> Vec<f32> {
+        let channel = |values: [f32; 3]| [values[0], values[1], values[1], values[2]];
+
+        let mut slot = Vec::new();
+        slot.extend(channel(mean).map(|n| n * count));
+        slot.extend(channel(gradient).map(|n| n * count));
+        slot.extend([count; 4]);
+        slot.repeat(workgroups)
+    }
+
+    // A histogram of each CFA channel with the given counts in the given bins
+    fn histogram(bins: [&[(usize, u32)]; 3]) -> Vec<u32> {
+        let mut histogram = vec![0; 4 * HISTOGRAM_BINS];
+        for (channel, color) in [0, 1, 1, 2].into_iter().enumerate() {
+            for &(bin, n) in bins[color] {
+                histogram[channel * HISTOGRAM_BINS + bin] = n;
+            }
+        }
+        histogram
+    }
+
+    fn assert_gains_eq(actual: Option<[f32; 4]>, expected: [f32; 4]) {
+        let actual = actual.unwrap();
+        for (actual_gain, expected_gain) in actual.iter().zip(expected) {
+            assert!(
+                (actual_gain - expected_gain).abs() < 1e-5,
+                "{actual:?} is not {expected:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn grey_world_and_grey_edge() {
+        let statistics = AwbStatistics::new(
+            &sums(6, [0.25, 0.5, 0.4], [0.02, 0.08, 0.05], 64.0),
+            &histogram([&[], &[], &[]]),
+        );
+
+        assert_gains_eq(
+            statistics.gains(AwbMethod::GreyWorld),
+            [2.0, 1.0, 1.0, 1.25],
+        );
+        assert_gains_eq(statistics.gains(AwbMethod::GreyEdge), [4.0, 1.0, 1.0, 1.6]);
+    }
+
+    #[test]
+    fn white_patch_and_percentile() {
+        let statistics = AwbStatistics::new(
+            &sums(1, [0.1; 3], [0.1; 3], 1.0),
+            &histogram([&[(63, 90), (200, 10)], &[(127, 100)], &[(31, 50), (95, 50)]]),
+        );
+
+        // Up to the top of the brightest bin, green at 128/256
+        assert_gains_eq(
+            statistics.gains(AwbMethod::WhitePatch),
+            [128.0 / 201.0, 1.0, 1.0, 128.0 / 96.0],
+        );
+        // 90 of red's 100 values are in its lower bin, half of blue's in its lower one
+        assert_gains_eq(
+            statistics.gains(AwbMethod::Percentile(0.9)),
+            [2.0, 1.0, 1.0, 128.0 / 96.0],
+        );
+        assert_gains_eq(
+            statistics.gains(AwbMethod::Percentile(0.5)),
+            [2.0, 1.0, 1.0, 4.0],
+        );
+    }
+
+    #[test]
+    fn no_statistics() {
+        let methods = [
+            AwbMethod::GreyWorld,
+            AwbMethod::WhitePatch,
+            AwbMethod::GreyEdge,
+            AwbMethod::Percentile(0.99),
+        ];
+        let empty_histogram = histogram([&[], &[], &[]]);
+
+        // No workgroups, workgroups without sites and sites that are all black
+        for sums in [
+            vec![],
+            sums(4, [0.5; 3], [0.1; 3], 0.0),
+            sums(4, [0.0; 3], [0.0; 3], 16.0),
+        ] {
+            let statistics = AwbStatistics::new(&sums, &empty_histogram);
+            for method in methods {
+                assert_eq!(statistics.gains(method), None, "{method:?}");
+            }
+        }
+
+        // A colour with no values at all
+        let statistics = AwbStatistics::new(
+            &sums(4, [0.5, 0.5, 0.0], [0.1, 0.1, 0.0], 16.0),
+            &histogram([&[(10, 5)], &[(20, 5)], &[]]),
+        );
+        for method in methods {
+            assert_eq!(statistics.gains(method), None, "{method:?}");
+        }
+    }
+}
@@ -0,0 +1,227 @@
+// Sites are stored row-major on a fixed 6×6 grid regardless of the pattern size, which is the
+// largest repeat pattern in use (Fujifilm X-Trans).
+const GRID: usize = 6;
+
+// Colour channels follow the order of COLOR_CORRECTION_GAINS: red, green (even rows), green (odd
+// rows) and blue. The shaders index the colour gains with them directly.
+const RED: u8 = 0;
+const GREEN_EVEN: u8 = 1;
+const GREEN_ODD: u8 = 2;
+const BLUE: u8 = 3;
+
+/// Color filter array layout, as described by the DNG `CFARepeatPatternDim` and `CFAPattern`
+/// tags.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CfaPattern {
+    size: [u32; 2],
+    sites: [u8; GRID * GRID],
+}
+
+impl CfaPattern {
+    /// Builds the pattern matching `SENSOR_INFO_COLOR_FILTER_ARRANGEMENT`.
+    pub fn from_color_filter_arrangement(color_filter_arrangement: i32) -> CfaPattern {
+        let pattern = match color_filter_arrangement {
+            0 /* RGGB */ => [0, 1, 1, 2],
+            1 /* GRBG */ => [1, 0, 2, 1],
+            2 /* GBRG */ => [1, 2, 0, 1],
+            3 /* BGGR */ => [2, 1, 1, 0],
+            5 /* MONO */ | 6 /* NIR */ => return CfaPattern::monochrome(),
+            _ => [0, 1, 1, 2],
+        };
+
+        CfaPattern::from_dng([2, 2], &pattern)
+    }
+
+    /// Builds a pattern from the DNG tags, where `pattern` holds `size[0] * size[1]` colour codes
+    /// in row-major order (0 red, 1 green, 2 blue).
+    pub fn from_dng(size: [u32; 2], pattern: &[u8]) -> CfaPattern {
+        let [width, height] = size.map(|n| n as usize);
+
+        assert!(
+            (1..=GRID).contains(&width) && (1..=GRID).contains(&height),
+            "CFA repeat pattern of {width}x{height} is not supported",
+        );
+        assert_eq!(pattern.len(), width * height, "CFA pattern size mismatch");
+
+        let mut sites = [0u8; GRID * GRID];
+
+        for y in 0..height {
+            for x in 0..width {
+                sites[y * GRID + x] = match pattern[y * width + x] {
+                    0 => RED,
+                    // Only Bayer sensors report separate gains for both greens
+                    1 if size == [2, 2] && y == 1 => GREEN_ODD,
+                    1 => GREEN_EVEN,
+                    2 => BLUE,
+                    code => panic!("Unsupported CFA colour code {code}"),
+                };
+            }
+        }
+
+        CfaPattern { size, sites }
+    }
+
+    /// Pattern of sensors without a colour filter array (monochrome and near infrared).
+    pub fn monochrome() -> CfaPattern {
+        CfaPattern::from_dng([1, 1], &[1])
+    }
+
+    pub fn size(&self) -> [u32; 2] {
+        self.size
+    }
+
+    pub fn is_bayer(&self) -> bool {
+        self.size == [2, 2]
+    }
+
+    pub fn is_monochrome(&self) -> bool {
+        self.size == [1, 1]
+    }
+
+    /// Colour channel of the site at raw coordinates `(x, y)`.
+    pub fn channel_at(&self, x: u32, y: u32) -> u8 {
+        let x = (x % self.size[0]) as usize;
+        let y = (y % self.size[1]) as usize;
+
+        self.sites[y * GRID + x]
+    }
+
+    /// Position of the first red site of a Bayer pattern, counting from `origin` which is given
+    /// in pattern coordinates. Other patterns have no such position and get zero.
+    pub fn first_red(&self, origin: [u32; 2]) -> [u32; 2] {
+        if !self.is_bayer() {
+            return [0, 0];
+        }
+
+        let [x, y] = origin;
+
+        match (self.channel_at(x, y), self.channel_at(x + 1, y)) {
+            (RED, _) => [0, 0],
+            (_, RED) => [1, 0],
+            (_, BLUE) => [0, 1],
+            _ => [1, 1],
+        }
+    }
+
+    /// Sites packed two bits each, sixteen per word, for use as a push constant.
+    pub fn packed(&self) -> [u32; 4] {
+        let mut packed = [0u32; 4];
+
+        for (index, &channel) in self.sites.iter().enumerate() {
+            packed[index / 16] |= (channel as u32) << ((index % 16) * 2);
+        }
+
+        packed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fujifilm X-Trans, as reported in CFAPattern
+    const X_TRANS: [u8; 36] = [
+        1, 1, 0, 1, 1, 2, //
+        1, 1, 2, 1, 1, 0, //
+        2, 0, 1, 0, 2, 1, //
+        1, 1, 2, 1, 1, 0, //
+        1, 1, 0, 1, 1, 2, //
+        0, 2, 1, 2, 0, 1, //
+    ];
+
+    // cfaChannel of shaders/common/cfa.slang
+    fn shader_channel(packed: [u32; 4], size: [u32; 2], x: u32, y: u32) -> u8 {
+        let index = (y % size[1]) * 6 + x % size[0];
+        ((packed[(index / 16) as usize] >> ((index % 16) * 2)) & 3) as u8
+    }
+
+    #[test]
+    fn bayer_arrangements() {
+        let expected = [
+            (0, [RED, GREEN_EVEN, GREEN_ODD, BLUE]),
+            (1, [GREEN_EVEN, RED, BLUE, GREEN_ODD]),
+            (2, [GREEN_EVEN, BLUE, RED, GREEN_ODD]),
+            (3, [BLUE, GREEN_EVEN, GREEN_ODD, RED]),
+        ];
+
+        for (arrangement, channels) in expected {
+            let pattern = CfaPattern::from_color_filter_arrangement(arrangement);
+            assert!(pattern.is_bayer());
+            assert_eq!(
+                [(0, 0), (1, 0), (0, 1), (1, 1)].map(|(x, y)| pattern.channel_at(x, y)),
+                channels,
+            );
+        }
+    }
+
+    #[test]
+    fn bayer_first_red() {
+        let rggb = CfaPattern::from_color_filter_arrangement(0);
+        assert_eq!(rggb.first_red([0, 0]), [0, 0]);
+        assert_eq!(rggb.first_red([1, 0]), [1, 0]);
+        assert_eq!(rggb.first_red([0, 1]), [0, 1]);
+        assert_eq!(rggb.first_red([1, 1]), [1, 1]);
+
+        let bggr = CfaPattern::from_color_filter_arrangement(3);
+        assert_eq!(bggr.first_red([0, 0]), [1, 1]);
+    }
+
+    #[test]
+    fn monochrome_arrangements() {
+        for arrangement in [5, 6] {
+            let pattern = CfaPattern::from_color_filter_arrangement(arrangement);
+            assert!(pattern.is_monochrome());
+            assert_eq!(pattern.channel_at(3, 7), GREEN_EVEN);
+        }
+    }
+
+    #[test]
+    fn x_trans_layout() {
+        let pattern = CfaPattern::from_dng([6, 6], &X_TRANS);
+        assert!(!pattern.is_bayer() && !pattern.is_monochrome());
+
+        for y in 0..12 {
+            for x in 0..12 {
+                let expected = match X_TRANS[((y % 6) * 6 + x % 6) as usize] {
+                    0 => RED,
+                    1 => GREEN_EVEN,
+                    _ => BLUE,
+                };
+                assert_eq!(pattern.channel_at(x, y), expected, "site {x},{y}");
+            }
+        }
+    }
+
+    #[test]
+    fn packing_matches_shader() {
+        for pattern in [
+            CfaPattern::from_dng([6, 6], &X_TRANS),
+            CfaPattern::from_color_filter_arrangement(1),
+            CfaPattern::monochrome(),
+        ] {
+            let packed = pattern.packed();
+            for y in 0..13 {
+                for x in 0..13 {
+                    assert_eq!(
+                        shader_channel(packed, pattern.size(), x, y),
+                        pattern.channel_at(x, y),
+                        "site {x},{y} of {:?}",
+                        pattern.size(),
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "not supported")]
+    fn oversized_pattern() {
+        CfaPattern::from_dng([8, 2], &[0; 16]);
+    }
+
+    #[test]
+    #[should_panic(expected = "size mismatch")]
+    fn truncated_pattern() {
+        CfaPattern::from_dng([6, 6], &X_TRANS[..30]);
+    }
+}
@@ -0,0 +1,889 @@
+// Host side colour science, following the DNG specification and the DNG SDK
+// (dng_color_spec.cpp, dng_temperature.cpp)
+
+use std::ops::Mul;
+
+use crate::pipeline::parameters::Parameters;
+
+/// Chromaticity of CIE D50, the white point of the profile connection space.
+pub const D50: [f64; 2] = [0.3457, 0.3585];
+/// Chromaticity of CIE D65, the white point of sRGB.
+pub const D65: [f64; 2] = [0.3127, 0.3290];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Matrix3(pub [[f64; 3]; 3]);
+
+impl Matrix3 {
+    pub const IDENTITY: Matrix3 = Matrix3([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]);
+
+    pub fn from_row_major(m: &[f32; 9]) -> Matrix3 {
+        Matrix3([
+            [m[0] as f64, m[1] as f64, m[2] as f64],
+            [m[3] as f64, m[4] as f64, m[5] as f64],
+            [m[6] as f64, m[7] as f64, m[8] as f64],
+        ])
+    }
+
+    pub fn to_row_major(self) -> [f32; 9] {
+        let m = self.0;
+        [
+            m[0][0], m[0][1], m[0][2], m[1][0], m[1][1], m[1][2], m[2][0], m[2][1], m[2][2],
+        ]
+        .map(|n| n as f32)
+    }
+
+    pub fn diagonal(v: [f64; 3]) -> Matrix3 {
+        Matrix3([[v[0], 0.0, 0.0], [0.0, v[1], 0.0], [0.0, 0.0, v[2]]])
+    }
+
+    pub fn transform(&self, v: [f64; 3]) -> [f64; 3] {
+        self.0
+            .map(|row| row[0] * v[0] + row[1] * v[1] + row[2] * v[2])
+    }
+
+    pub fn scale(&self, factor: f64) -> Matrix3 {
+        Matrix3(self.0.map(|row| row.map(|n| n * factor)))
+    }
+
+    /// `weight * a + (1 - weight) * b`
+    pub fn lerp(a: &Matrix3, b: &Matrix3, weight: f64) -> Matrix3 {
+        Matrix3(std::array::from_fn(|i| {
+            std::array::from_fn(|j| weight * a.0[i][j] + (1.0 - weight) * b.0[i][j])
+        }))
+    }
+
+    pub fn inverse(&self) -> Option<Matrix3> {
+        let m = &self.0;
+
+        let cofactor = |r0: usize, r1: usize, c0: usize, c1: usize| {
+            m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+        };
+
+        let adjugate = [
+            [
+                cofactor(1, 2, 1, 2),
+                -cofactor(0, 2, 1, 2),
+                cofactor(0, 1, 1, 2),
+            ],
+            [
+                -cofactor(1, 2, 0, 2),
+                cofactor(0, 2, 0, 2),
+                -cofactor(0, 1, 0, 2),
+            ],
+            [
+                cofactor(1, 2, 0, 1),
+                -cofactor(0, 2, 0, 1),
+                cofactor(0, 1, 0, 1),
+            ],
+        ];
+
+        let determinant =
+            m[0][0] * adjugate[0][0] + m[0][1] * adjugate[1][0] + m[0][2] * adjugate[2][0];
+
+        if determinant.abs() < 1e-12 {
+            return None;
+        }
+
+        Some(Matrix3(adjugate).scale(1.0 / determinant))
+    }
+}
+
+impl Mul for Matrix3 {
+    type Output = Matrix3;
+
+    fn mul(self, rhs: Matrix3) -> Matrix3 {
+        Matrix3(std::array::from_fn(|i| {
+            std::array::from_fn(|j| (0..3).map(|k| self.0[i][k] * rhs.0[k][j]).sum())
+        }))
+    }
+}
+
+pub fn xy_to_xyz(xy: [f64; 2]) -> [f64; 3] {
+    let [x, y] = [xy[0].clamp(1e-6, 0.999999), xy[1].clamp(1e-6, 0.999999)];
+    [x / y, 1.0, (1.0 - x - y).max(1e-6) / y]
+}
+
+/// Linear RGB to XYZ for an RGB space given by its primaries and white point, scaled so that
+/// RGB white has a luminance of one.
+pub fn rgb_to_xyz(primaries: [[f64; 2]; 3], white: [f64; 2]) -> Matrix3 {
+    let [r, g, b] = primaries.map(xy_to_xyz);
+    let primaries = Matrix3([[r[0], g[0], b[0]], [r[1], g[1], b[1]], [r[2], g[2], b[2]]]);
+
+    let scale = primaries
+        .inverse()
+        .expect("Primaries are collinear")
+        .transform(xy_to_xyz(white));
+
+    primaries * Matrix3::diagonal(scale)
+}
+
+pub fn xyz_to_xy(xyz: [f64; 3]) -> [f64; 2] {
+    let sum = xyz[0] + xyz[1] + xyz[2];
+    if sum > 0.0 {
+        [xyz[0] / sum, xyz[1] / sum]
+    } else {
+        D50
+    }
+}
+
+/// Encoding of linear values, `scale * x^exponent - offset` above `linear_cutoff` and
+/// `linear_slope * x` below it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TransferFunction {
+    pub linear_slope: f32,
+    pub linear_cutoff: f32,
+    pub scale: f32,
+    pub offset: f32,
+    pub exponent: f32,
+}
+
+impl TransferFunction {
+    /// IEC 61966-2-1
+    pub const SRGB: TransferFunction = TransferFunction {
+        linear_slope: 12.92,
+        linear_cutoff: 0.0031308,
+        scale: 1.055,
+        offset: 0.055,
+        exponent: 1.0 / 2.4,
+    };
+
+    /// ITU-R BT.709 and BT.2020
+    pub const REC709: TransferFunction = TransferFunction {
+        linear_slope: 4.5,
+        linear_cutoff: 0.018053968,
+        scale: 1.0992968,
+        offset: 0.0992968,
+        exponent: 0.45,
+    };
+
+    /// Adobe RGB (1998), a pure power of 563/256
+    pub const ADOBE_RGB: TransferFunction = TransferFunction {
+        linear_slope: 0.0,
+        linear_cutoff: 0.0,
+        scale: 1.0,
+        offset: 0.0,
+        exponent: 256.0 / 563.0,
+    };
+
+    /// ROMM RGB (ISO 22028-2)
+    pub const PRO_PHOTO_RGB: TransferFunction = TransferFunction {
+        linear_slope: 16.0,
+        linear_cutoff: 1.0 / 512.0,
+        scale: 1.0,
+        offset: 0.0,
+        exponent: 1.0 / 1.8,
+    };
+}
+
+/// Output colour space, choosing the primaries, white point and transfer function together.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorSpace {
+    Srgb,
+    DisplayP3,
+    Rec2020,
+    AdobeRgb,
+    ProPhotoRgb,
+}
+
+impl ColorSpace {
+    /// Maps the id of an Android `ColorSpace.Named`, falling back to sRGB for the ones that are
+    /// not supported as output.
+    pub fn from_android_id(id: i32) -> ColorSpace {
+        match id {
+            5 /* BT2020 */ => ColorSpace::Rec2020,
+            7 /* DISPLAY_P3 */ => ColorSpace::DisplayP3,
+            10 /* ADOBE_RGB */ => ColorSpace::AdobeRgb,
+            11 /* PRO_PHOTO_RGB */ => ColorSpace::ProPhotoRgb,
+            _ => ColorSpace::Srgb,
+        }
+    }
+
+    /// Chromaticities of the red, green and blue primaries.
+    pub fn primaries(&self) -> [[f64; 2]; 3] {
+        match self {
+            ColorSpace::Srgb => [[0.64, 0.33], [0.30, 0.60], [0.15, 0.06]],
+            ColorSpace::DisplayP3 => [[0.680, 0.320], [0.265, 0.690], [0.150, 0.060]],
+            ColorSpace::Rec2020 => [[0.708, 0.292], [0.170, 0.797], [0.131, 0.046]],
+            ColorSpace::AdobeRgb => [[0.64, 0.33], [0.21, 0.71], [0.15, 0.06]],
+            ColorSpace::ProPhotoRgb => [[0.7347, 0.2653], [0.1596, 0.8404], [0.0366, 0.0001]],
+        }
+    }
+
+    pub fn white(&self) -> [f64; 2] {
+        match self {
+            ColorSpace::ProPhotoRgb => D50,
+            _ => D65,
+        }
+    }
+
+    pub fn transfer_function(&self) -> TransferFunction {
+        match self {
+            ColorSpace::Srgb | ColorSpace::DisplayP3 => TransferFunction::SRGB,
+            ColorSpace::Rec2020 => TransferFunction::REC709,
+            ColorSpace::AdobeRgb => TransferFunction::ADOBE_RGB,
+            ColorSpace::ProPhotoRgb => TransferFunction::PRO_PHOTO_RGB,
+        }
+    }
+
+    /// Linear RGB to XYZ relative to the white point of the colour space.
+    pub fn to_xyz(&self) -> Matrix3 {
+        rgb_to_xyz(self.primaries(), self.white())
+    }
+
+    /// XYZ D50 of the profile connection space to linear RGB, adapting D50 to the white point
+    /// of the colour space.
+    pub fn pcs_to_rgb(&self, adaptation: ChromaticAdaptation) -> Matrix3 {
+        self.to_xyz().inverse().unwrap() * adaptation.matrix(D50, self.white())
+    }
+}
+
+/// Compression of the distance of each channel from the largest one, bringing colours outside
+/// the output gamut back in along lines of constant hue (ACES reference gamut compression, see
+/// shaders/finishing_23.slang). Distances are relative to the largest channel, one is on the
+/// gamut boundary.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GamutCompression {
+    /// Distance of cyan, magenta and yellow below which colours are left as they are
+    pub threshold: [f32; 3],
+    /// Distance of cyan, magenta and yellow that ends up on the gamut boundary
+    pub limit: [f32; 3],
+    /// Steepness of the compression curve
+    pub power: f32,
+}
+
+impl GamutCompression {
+    /// Parameters of ACES 1.3
+    pub const ACES: GamutCompression = GamutCompression {
+        threshold: [0.815, 0.803, 0.880],
+        limit: [1.147, 1.264, 1.312],
+        power: 1.2,
+    };
+}
+
+/// Chromatic adaptation transform, named after the cone response domain it scales in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChromaticAdaptation {
+    Bradford,
+    Cat02,
+    VonKries,
+}
+
+impl ChromaticAdaptation {
+    fn cone_response(&self) -> Matrix3 {
+        match self {
+            ChromaticAdaptation::Bradford => Matrix3([
+                [0.8951, 0.2664, -0.1614],
+                [-0.7502, 1.7135, 0.0367],
+                [0.0389, -0.0685, 1.0296],
+            ]),
+            ChromaticAdaptation::Cat02 => Matrix3([
+                [0.7328, 0.4296, -0.1624],
+                [-0.7036, 1.6975, 0.0061],
+                [0.0030, 0.0136, 0.9834],
+            ]),
+            // Hunt-Pointer-Estevez, normalized to D65
+            ChromaticAdaptation::VonKries => Matrix3([
+                [0.40024, 0.70760, -0.08081],
+                [-0.22630, 1.16532, 0.04570],
+                [0.0, 0.0, 0.91822],
+            ]),
+        }
+    }
+
+    /// Linear adaptation of XYZ values from one white chromaticity to another.
+    pub fn matrix(&self, from: [f64; 2], to: [f64; 2]) -> Matrix3 {
+        let cone_response = self.cone_response();
+
+        let from = cone_response.transform(xy_to_xyz(from));
+        let to = cone_response.transform(xy_to_xyz(to));
+
+        // Limits the gain like the DNG SDK, for white points far off the locus
+        let scale = [0, 1, 2].map(|i| {
+            if from[i] > 0.0 {
+                (to[i] / from[i]).clamp(0.1, 10.0)
+            } else {
+                10.0
+            }
+        });
+
+        cone_response.inverse().unwrap() * Matrix3::diagonal(scale) * cone_response
+    }
+}
+
+// Robertson's isotemperature lines: reciprocal megakelvin, u, v and slope
+const TEMPERATURE_TABLE: [[f64; 4]; 31] = [
+    [0.0, 0.18006, 0.26352, -0.24341],
+    [10.0, 0.18066, 0.26589, -0.25479],
+    [20.0, 0.18133, 0.26846, -0.26876],
+    [30.0, 0.18208, 0.27119, -0.28539],
+    [40.0, 0.18293, 0.27407, -0.30470],
+    [50.0, 0.18388, 0.27709, -0.32675],
+    [60.0, 0.18494, 0.28021, -0.35156],
+    [70.0, 0.18611, 0.28342, -0.37915],
+    [80.0, 0.18740, 0.28668, -0.40955],
+    [90.0, 0.18880, 0.28997, -0.44278],
+    [100.0, 0.19032, 0.29326, -0.47888],
+    [125.0, 0.19462, 0.30141, -0.58204],
+    [150.0, 0.19962, 0.30921, -0.70471],
+    [175.0, 0.20525, 0.31647, -0.84901],
+    [200.0, 0.21142, 0.32312, -1.0182],
+    [225.0, 0.21807, 0.32909, -1.2168],
+    [250.0, 0.22511, 0.33439, -1.4512],
+    [275.0, 0.23247, 0.33904, -1.7298],
+    [300.0, 0.24010, 0.34308, -2.0637],
+    [325.0, 0.24792, 0.34655, -2.4681],
+    [350.0, 0.25591, 0.34951, -2.9641],
+    [375.0, 0.26400, 0.35200, -3.5814],
+    [400.0, 0.27218, 0.35407, -4.3633],
+    [425.0, 0.28039, 0.35577, -5.3762],
+    [450.0, 0.28863, 0.35714, -6.7262],
+    [475.0, 0.29685, 0.35823, -8.5955],
+    [500.0, 0.30505, 0.35907, -11.324],
+    [525.0, 0.31320, 0.35968, -15.628],
+    [550.0, 0.32129, 0.36011, -23.325],
+    [575.0, 0.32931, 0.36038, -40.770],
+    [600.0, 0.33724, 0.36051, -116.45],
+];
+
+// Scale factor between distances in uv space and tint
+const TINT_SCALE: f64 = -3000.0;
+
+/// Correlated colour temperature in kelvin and tint of a chromaticity.
+pub fn xy_to_temperature(xy: [f64; 2]) -> (f64, f64) {
+    let [x, y] = xy;
+
+    let u = 2.0 * x / (1.5 - x + 6.0 * y);
+    let v = 3.0 * y / (1.5 - x + 6.0 * y);
+
+    let mut last_dt = 0.0;
+    let mut last_du = 0.0;
+    let mut last_dv = 0.0;
+
+    for index in 1..TEMPERATURE_TABLE.len() {
+        let [_, line_u, line_v, slope] = TEMPERATURE_TABLE[index];
+
+        // Slope converted to a unit vector
+        let length = (1.0 + slope * slope).sqrt();
+        let du = 1.0 / length;
+        let dv = slope / length;
+
+        // Distance above or below this isotemperature line
+        let mut dt = -(u - line_u) * dv + (v - line_v) * du;
+
+        if dt <= 0.0 || index == TEMPERATURE_TABLE.len() - 1 {
+            dt = -dt.min(0.0);
+
+            let f = if index == 1 { 0.0 } else { dt / (last_dt + dt) };
+
+            let [r0, u0, v0, _] = TEMPERATURE_TABLE[index - 1];
+            let temperature = 1.0e6 / (r0 * f + TEMPERATURE_TABLE[index][0] * (1.0 - f));
+
+            let uu = u - (u0 * f + line_u * (1.0 - f));
+            let vv = v - (v0 * f + line_v * (1.0 - f));
+
+            let du = du * (1.0 - f) + last_du * f;
+            let dv = dv * (1.0 - f) + last_dv * f;
+            let length = (du * du + dv * dv).sqrt();
+
+            let tint = (uu * du / length + vv * dv / length) * TINT_SCALE;
+
+            return (temperature, tint);
+        }
+
+        last_dt = dt;
+        last_du = du;
+        last_dv = dv;
+    }
+
+    unreachable!()
+}
+
+/// Chromaticity of a correlated colour temperature in kelvin and tint.
+pub fn temperature_to_xy(temperature: f64, tint: f64) -> [f64; 2] {
+    let r = 1.0e6 / temperature;
+    let offset = tint / TINT_SCALE;
+
+    let index = (0..TEMPERATURE_TABLE.len() - 2)
+        .find(|&index| r < TEMPERATURE_TABLE[index + 1][0])
+        .unwrap_or(TEMPERATURE_TABLE.len() - 2);
+
+    let [r0, u0, v0, slope0] = TEMPERATURE_TABLE[index];
+    let [r1, u1, v1, slope1] = TEMPERATURE_TABLE[index + 1];
+
+    let f = (r1 - r) / (r1 - r0);
+
+    let mut u = u0 * f + u1 * (1.0 - f);
+    let mut v = v0 * f + v1 * (1.0 - f);
+
+    // Interpolated unit vector along the isotemperature lines
+    let length0 = (1.0 + slope0 * slope0).sqrt();
+    let length1 = (1.0 + slope1 * slope1).sqrt();
+    let du = f / length0 + (1.0 - f) / length1;
+    let dv = slope0 / length0 * f + slope1 / length1 * (1.0 - f);
+    let length = (du * du + dv * dv).sqrt();
+
+    u += du / length * offset;
+    v += dv / length * offset;
+
+    [1.5 * u / (u - 4.0 * v + 2.0), v / (u - 4.0 * v + 2.0)]
+}
+
+/// Colour temperature of an EXIF `LightSource`, as used by `CalibrationIlluminant` and
+/// `SENSOR_REFERENCE_ILLUMINANT`. Unknown illuminants get zero.
+pub fn illuminant_temperature(illuminant: i32) -> f64 {
+    match illuminant {
+        17 /* Standard light A */ | 3 /* Tungsten */ => 2850.0,
+        24 /* ISO studio tungsten */ => 3200.0,
+        23 /* D50 */ => 5000.0,
+        20 /* D55 */ | 1 /* Daylight */ | 9 /* Fine weather */ | 4 /* Flash */
+        | 18 /* Standard light B */ => 5500.0,
+        21 /* D65 */ | 19 /* Standard light C */ | 10 /* Cloudy weather */ => 6500.0,
+        22 /* D75 */ | 11 /* Shade */ => 7500.0,
+        12 /* Daylight fluorescent */ => (5700.0 + 7100.0) * 0.5,
+        13 /* Day white fluorescent */ => (4600.0 + 5500.0) * 0.5,
+        14 /* Cool white fluorescent */ | 2 /* Fluorescent */ => (3800.0 + 4500.0) * 0.5,
+        15 /* White fluorescent */ => (3250.0 + 3800.0) * 0.5,
+        16 /* Warm white fluorescent */ => (2600.0 + 3250.0) * 0.5,
+        _ => 0.0,
+    }
+}
+
+/// Dual-illuminant calibration of a camera, the DNG `ColorMatrix`, `CameraCalibration`,
+/// `AnalogBalance`, `ForwardMatrix` and `CalibrationIlluminant` tags.
+///
+/// Each tag is interpolated on its own for the scene illuminant and the results are combined
+/// afterwards, as the DNG SDK does.
+pub struct ColorProfile {
+    // XYZ to reference camera
+    color_matrix_1: Matrix3,
+    color_matrix_2: Matrix3,
+    // Reference camera to individual camera
+    camera_calibration_1: Matrix3,
+    camera_calibration_2: Matrix3,
+    analog_balance: Matrix3,
+    // White balanced reference camera to XYZ D50
+    forward_matrix_1: Option<Matrix3>,
+    forward_matrix_2: Option<Matrix3>,
+
+    temperature_1: f64,
+    temperature_2: f64,
+}
+
+impl ColorProfile {
+    pub fn new(parameters: &Parameters) -> ColorProfile {
+        let forward_matrix_1 = parameters
+            .forward_matrix_1
+            .as_ref()
+            .map(Matrix3::from_row_major);
+        // Single illuminant profiles may only have the first forward matrix
+        let forward_matrix_2 = parameters
+            .forward_matrix_2
+            .as_ref()
+            .map(Matrix3::from_row_major)
+            .or(forward_matrix_1);
+
+        ColorProfile {
+            color_matrix_1: Matrix3::from_row_major(&parameters.color_matrix_1),
+            color_matrix_2: Matrix3::from_row_major(&parameters.color_matrix_2),
+            camera_calibration_1: Matrix3::from_row_major(&parameters.camera_calibration_1),
+            camera_calibration_2: Matrix3::from_row_major(&parameters.camera_calibration_2),
+            analog_balance: Matrix3::diagonal(parameters.analog_balance.map(|n| n as f64)),
+            forward_matrix_1,
+            forward_matrix_2,
+            temperature_1: illuminant_temperature(parameters.calibration_illuminant_1),
+            temperature_2: illuminant_temperature(parameters.calibration_illuminant_2),
+        }
+    }
+
+    // Weight of the first calibration at a colour temperature, interpolating linearly in
+    // inverse temperature between both calibration illuminants
+    fn weight(&self, temperature: f64) -> f64 {
+        let (t1, t2) = (self.temperature_1, self.temperature_2);
+
+        // Single illuminant profiles only have a usable first calibration
+        if t1 <= 0.0 || t2 <= 0.0 || t1 == t2 {
+            return 1.0;
+        }
+
+        let (low, high, low_is_first) = if t1 < t2 {
+            (t1, t2, true)
+        } else {
+            (t2, t1, false)
+        };
+
+        let weight_of_low = if temperature <= low {
+            1.0
+        } else if temperature >= high {
+            0.0
+        } else {
+            (1.0 / temperature - 1.0 / high) / (1.0 / low - 1.0 / high)
+        };
+
+        if low_is_first {
+            weight_of_low
+        } else {
+            1.0 - weight_of_low
+        }
+    }
+
+    // Reference camera to individual camera, analog balance included
+    fn camera_calibration(&self, weight: f64) -> Matrix3 {
+        self.analog_balance
+            * Matrix3::lerp(
+                &self.camera_calibration_1,
+                &self.camera_calibration_2,
+                weight,
+            )
+    }
+
+    fn xyz_to_camera(&self, white: [f64; 2]) -> Matrix3 {
+        let (temperature, _) = xy_to_temperature(white);
+        let weight = self.weight(temperature);
+
+        let color_matrix = Matrix3::lerp(&self.color_matrix_1, &self.color_matrix_2, weight);
+
+        self.camera_calibration(weight) * color_matrix
+    }
+
+    /// White chromaticity of a camera neutral, found iteratively since the colour matrix
+    /// depends on it. None when the colour matrices are missing or not invertible.
+    pub fn neutral_to_xy(&self, neutral: [f64; 3]) -> Option<[f64; 2]> {
+        const MAX_PASSES: usize = 30;
+
+        let mut last = D50;
+
+        for pass in 0..MAX_PASSES {
+            let camera_to_xyz = self.xyz_to_camera(last).inverse()?;
+            let mut next = xyz_to_xy(camera_to_xyz.transform(neutral));
+            if !next.iter().all(|n| n.is_finite()) {
+                return None;
+            }
+
+            if (next[0] - last[0]).abs() + (next[1] - last[1]).abs() < 1e-7 {
+                return Some(next);
+            }
+
+            // Not converging at this point most likely means it oscillates between two values
+            if pass == MAX_PASSES - 1 {
+                next = [(last[0] + next[0]) * 0.5, (last[1] + next[1]) * 0.5];
+            }
+
+            last = next;
+        }
+
+        Some(last)
+    }
+
+    /// Camera neutral of a white chromaticity, the inverse of `neutral_to_xy`.
+    pub fn xy_to_neutral(&self, white: [f64; 2]) -> [f64; 3] {
+        self.xyz_to_camera(white).transform(xy_to_xyz(white))
+    }
+
+    /// Matrix from white balanced camera RGB to XYZ D50 for the scene illuminant given by the
+    /// camera neutral. Built from the forward matrices when there are any, and by inverting the
+    /// colour matrices otherwise. None when neither are usable.
+    pub fn camera_to_pcs(&self, neutral: [f64; 3]) -> Option<Matrix3> {
+        // Without usable colour matrices the forward matrices are interpolated for daylight
+        let white = self.neutral_to_xy(neutral);
+        let (temperature, _) = xy_to_temperature(white.unwrap_or(D65));
+        let weight = self.weight(temperature);
+
+        match (self.forward_matrix_1, self.forward_matrix_2) {
+            (Some(forward_matrix_1), Some(forward_matrix_2)) => {
+                let forward_matrix = Matrix3::lerp(&forward_matrix_1, &forward_matrix_2, weight);
+                let reference_matrix = self.camera_calibration(weight).inverse()?;
+
+                // The forward matrices expect white balanced reference camera values, so the
+                // individual camera values are mapped first and balanced with the matching
+                // neutral
+                let reference_neutral = reference_matrix.transform(neutral);
+                let balance = Matrix3::diagonal(reference_neutral.map(|n| 1.0 / n));
+
+                Some(forward_matrix * balance * reference_matrix * Matrix3::diagonal(neutral))
+            }
+            _ => {
+                // Adapts the D50 white to the scene white before going to camera space, scaled so
+                // the white has a green of one like the white balanced values. The neutral
+                // normalized to green then takes those back to camera values, and white to D50
+                // as with the forward matrices.
+                let white = white?;
+                let pcs_to_camera =
+                    self.xyz_to_camera(white) * ChromaticAdaptation::Bradford.matrix(D50, white);
+                let green = pcs_to_camera.transform(xy_to_xyz(D50))[1];
+
+                let camera_to_pcs = pcs_to_camera.scale(1.0 / green).inverse()?;
+
+                Some(camera_to_pcs * Matrix3::diagonal(neutral.map(|n| n / neutral[1])))
+            }
+        }
+    }
+}
+
+/// Colour gains that make a camera neutral white, with green at one.
+pub fn neutral_to_gains(neutral: [f64; 3]) -> [f32; 4] {
+    let green = neutral[1] / neutral[0];
+    let blue = neutral[1] / neutral[2];
+    [green as f32, 1.0, 1.0, blue as f32]
+}
+
+/// Camera neutral matching the colour gains, in the order of `COLOR_CORRECTION_GAINS`. Gains
+/// that are not positive, such as the zeros of a capture result without any, have none.
+pub fn gains_to_neutral(color_gains: [f32; 4]) -> Option<[f64; 3]> {
+    if !color_gains
+        .iter()
+        .all(|&gain| gain.is_finite() && gain > 0.0)
+    {
+        return None;
+    }
+
+    let green = (color_gains[1] as f64 + color_gains[2] as f64) * 0.5;
+    Some([
+        1.0 / color_gains[0] as f64,
+        1.0 / green,
+        1.0 / color_gains[3] as f64,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A made up but typical profile, with calibrations and an analog balance so each tag has to
+    // be interpolated on its own. Illuminant A first, D65 second.
+    const COLOR_MATRIX_1: [f32; 9] = [
+        0.7034, -0.0804, -0.1014, -0.4420, 1.2564, 0.2058, -0.0851, 0.1994, 0.5758,
+    ];
+    const COLOR_MATRIX_2: [f32; 9] = [
+        0.6722, -0.0635, -0.0963, -0.4287, 1.2460, 0.2028, -0.0908, 0.2162, 0.5668,
+    ];
+    const CAMERA_CALIBRATION_1: [f32; 9] = [1.0213, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.9712];
+    const CAMERA_CALIBRATION_2: [f32; 9] =
+        [1.0108, 0.0021, 0.0, 0.0, 1.0, 0.0, 0.0, -0.0034, 0.9855];
+    const FORWARD_MATRIX_1: [f32; 9] = [
+        0.8047, 0.0951, 0.0644, 0.3155, 0.8094, -0.1249, 0.0214, -0.2398, 1.0433,
+    ];
+    const FORWARD_MATRIX_2: [f32; 9] = [
+        0.7868, 0.1150, 0.0624, 0.2939, 0.8877, -0.1816, 0.0142, -0.1547, 0.9654,
+    ];
+    const ANALOG_BALANCE: [f32; 3] = [0.98, 1.0, 1.03];
+
+    // Daylight and tungsten
+    const NEUTRALS: [[f64; 3]; 2] = [[0.5, 1.0, 0.625], [0.8, 1.0, 0.42]];
+
+    // Single illuminant profiles keep their only calibration, D65 here, in the first tags
+    fn profile(dual_illuminant: bool, forward_matrices: bool) -> ColorProfile {
+        let matrix = Matrix3::from_row_major;
+        let (first, second) = if dual_illuminant { (0, 1) } else { (1, 1) };
+        let color_matrices = [matrix(&COLOR_MATRIX_1), matrix(&COLOR_MATRIX_2)];
+        let camera_calibrations = [matrix(&CAMERA_CALIBRATION_1), matrix(&CAMERA_CALIBRATION_2)];
+        let forward_matrices =
+            forward_matrices.then(|| [matrix(&FORWARD_MATRIX_1), matrix(&FORWARD_MATRIX_2)]);
+
+        ColorProfile {
+            color_matrix_1: color_matrices[first],
+            color_matrix_2: color_matrices[second],
+            camera_calibration_1: camera_calibrations[first],
+            camera_calibration_2: camera_calibrations[second],
+            analog_balance: Matrix3::diagonal(ANALOG_BALANCE.map(|n| n as f64)),
+            forward_matrix_1: forward_matrices.map(|matrices| matrices[first]),
+            forward_matrix_2: forward_matrices.map(|matrices| matrices[second]),
+            temperature_1: if dual_illuminant { 2850.0 } else { 6500.0 },
+            temperature_2: if dual_illuminant { 6500.0 } else { 0.0 },
+        }
+    }
+
+    fn assert_matrix_eq(actual: Matrix3, expected: [[f64; 3]; 3]) {
+        for (actual, expected) in actual.0.iter().flatten().zip(expected.iter().flatten()) {
+            assert!(
+                (actual - expected).abs() < 1e-5,
+                "{actual:?} is not {expected:?}"
+            );
+        }
+    }
+
+    // dng_color_spec's camera to PCS matrix for each neutral, times its camera white to take
+    // white balanced values like camera_to_pcs does, from a transcription of the DNG SDK
+    #[test]
+    fn camera_to_pcs_with_forward_matrices() {
+        let dual_illuminant = [
+            [
+                [0.793004, 0.108674, 0.062522],
+                [0.299223, 0.870228, -0.169451],
+                [0.015698, -0.167473, 0.976675],
+            ],
+            // Below the temperature of illuminant A, so the first forward matrix as it is
+            [
+                [0.804700, 0.095100, 0.064400],
+                [0.315500, 0.809400, -0.124900],
+                [0.021400, -0.239800, 1.043300],
+            ],
+        ];
+        let single_illuminant = [
+            [
+                [0.790052, 0.112096, 0.062052],
+                [0.295115, 0.885473, -0.180588],
+                [0.014259, -0.149379, 0.960021],
+            ],
+            [
+                [0.788829, 0.113487, 0.061884],
+                [0.294658, 0.885440, -0.180098],
+                [0.014237, -0.146754, 0.957417],
+            ],
+        ];
+
+        for (index, neutral) in NEUTRALS.iter().enumerate() {
+            assert_matrix_eq(
+                profile(true, true).camera_to_pcs(*neutral).unwrap(),
+                dual_illuminant[index],
+            );
+            assert_matrix_eq(
+                profile(false, true).camera_to_pcs(*neutral).unwrap(),
+                single_illuminant[index],
+            );
+        }
+    }
+
+    #[test]
+    fn camera_to_pcs_from_color_matrices() {
+        let dual_illuminant = [
+            [
+                [0.779875, 0.042521, 0.141900],
+                [0.265748, 0.876016, -0.141765],
+                [0.021202, -0.291995, 1.095898],
+            ],
+            [
+                [0.839206, -0.074149, 0.199238],
+                [0.261035, 0.795846, -0.056881],
+                [0.067034, -0.505613, 1.263683],
+            ],
+        ];
+        let single_illuminant = [
+            [
+                [0.784592, 0.036187, 0.143517],
+                [0.265765, 0.875671, -0.141436],
+                [0.022100, -0.298872, 1.101876],
+            ],
+            [
+                [0.860839, -0.106544, 0.210001],
+                [0.254148, 0.800946, -0.055094],
+                [0.080847, -0.572378, 1.316636],
+            ],
+        ];
+
+        for (index, neutral) in NEUTRALS.iter().enumerate() {
+            assert_matrix_eq(
+                profile(true, false).camera_to_pcs(*neutral).unwrap(),
+                dual_illuminant[index],
+            );
+            assert_matrix_eq(
+                profile(false, false).camera_to_pcs(*neutral).unwrap(),
+                single_illuminant[index],
+            );
+        }
+    }
+
+    #[test]
+    fn white_balanced_white_to_d50() {
+        let d50 = xy_to_xyz(D50);
+
+        for neutral in NEUTRALS {
+            for (dual_illuminant, forward_matrices) in
+                [(true, true), (true, false), (false, true), (false, false)]
+            {
+                let profile = profile(dual_illuminant, forward_matrices);
+                let white = profile.camera_to_pcs(neutral).unwrap().transform([1.0; 3]);
+
+                // Forward matrices map to the rounded XYZ of D50 of the specification
+                for channel in 0..3 {
+                    assert!((white[channel] - d50[channel]).abs() < 5e-4, "{white:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn neutral_stays_neutral() {
+        let adaptations = [
+            ChromaticAdaptation::Bradford,
+            ChromaticAdaptation::Cat02,
+            ChromaticAdaptation::VonKries,
+        ];
+        let color_spaces = [
+            ColorSpace::Srgb,
+            ColorSpace::DisplayP3,
+            ColorSpace::Rec2020,
+            ColorSpace::AdobeRgb,
+            ColorSpace::ProPhotoRgb,
+        ];
+
+        for neutral in NEUTRALS {
+            for forward_matrices in [true, false] {
+                let camera_to_pcs = profile(true, forward_matrices)
+                    .camera_to_pcs(neutral)
+                    .unwrap();
+                // Forward matrices map to the XYZ of D50 as the specification rounds it, a
+                // little off the chromaticity everything else uses
+                let tolerance = if forward_matrices { 1e-3 } else { 1e-6 };
+
+                for adaptation in adaptations {
+                    for color_space in color_spaces {
+                        let camera_to_output = color_space.pcs_to_rgb(adaptation) * camera_to_pcs;
+                        let [r, g, b] = camera_to_output.transform([1.0; 3]);
+
+                        let context = format!("{adaptation:?} {color_space:?} {r} {g} {b}");
+                        assert!(
+                            (r - g).abs() < tolerance && (b - g).abs() < tolerance,
+                            "{context}"
+                        );
+                        // White balanced white is the white of the output
+                        assert!((g - 1.0).abs() < tolerance, "{context}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn missing_color_matrices() {
+        // Zeroed, as the caller leaves the matrices a device does not report
+        let zero = Matrix3([[0.0; 3]; 3]);
+        let only_forward_matrices = ColorProfile {
+            color_matrix_1: zero,
+            color_matrix_2: zero,
+            ..profile(true, true)
+        };
+        let nothing = ColorProfile {
+            forward_matrix_1: None,
+            forward_matrix_2: None,
+            ..only_forward_matrices
+        };
+
+        for neutral in NEUTRALS {
+            assert_eq!(only_forward_matrices.neutral_to_xy(neutral), None);
+            // Interpolated for daylight, so the second forward matrix
+            let forward_matrices = profile(false, true).camera_to_pcs(neutral).unwrap();
+            let camera_to_pcs = only_forward_matrices.camera_to_pcs(neutral).unwrap();
+            for (actual, expected) in camera_to_pcs
+                .0
+                .iter()
+                .flatten()
+                .zip(forward_matrices.0.iter().flatten())
+            {
+                assert!((actual - expected).abs() < 1e-4, "{camera_to_pcs:?}");
+            }
+
+            assert_eq!(nothing.camera_to_pcs(neutral), None);
+        }
+    }
+
+    #[test]
+    fn gains_to_neutral_rejects_missing_gains() {
+        assert_eq!(
+            gains_to_neutral([2.0, 1.0, 1.0, 4.0]),
+            Some([0.5, 1.0, 0.25])
+        );
+        assert_eq!(gains_to_neutral([0.0; 4]), None);
+        assert_eq!(gains_to_neutral([2.0, 1.0, 1.0, -1.6]), None);
+        assert_eq!(gains_to_neutral([f32::NAN, 1.0, 1.0, 1.6]), None);
+    }
+}
@@ -11,12 +11,17 @@ use vulkano::{
     memory::allocator::StandardMemoryAllocator,
 };
 
+use crate::pipeline::stage;
+
 pub struct Context {
     pub device: Arc<Device>,
     pub queue: Arc<Queue>,
     pub memory_allocator: Arc<StandardMemoryAllocator>,
     pub descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
     pub command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+
+    // Workgroup size of every compute stage, chosen from the device limits
+    pub work_group_size: [u32; 2],
 }
 
 impl Context {
@@ -51,6 +56,14 @@ impl Context {
 
         // info!("Queue family with compute {:?}", queue_family_index);
 
+        let work_group_size = {
+            let properties = physical_device.properties();
+            stage::work_group_size(
+                properties.max_compute_work_group_size,
+                properties.max_compute_work_group_invocations,
+            )
+        };
+
         let (device, mut queues) = Device::new(
             physical_device,
             DeviceCreateInfo {
@@ -88,6 +101,7 @@ impl Context {
             memory_allocator,
             descriptor_set_allocator,
             command_buffer_allocator,
+            work_group_size,
         })
     }
 }
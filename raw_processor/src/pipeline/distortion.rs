@@ -0,0 +1,437 @@
+// Geometric lens distortion and lateral chromatic aberration, following the Camera2
+// `LENS_DISTORTION` model and the DNG `WarpRectilinear` opcode, see shaders/finishing_12.slang
+// to shaders/finishing_14.slang
+
+use crate::pipeline::color::Matrix3;
+
+/// How the corrected image is framed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DistortionCrop {
+    /// Every pixel of the sensor stays in the image, corners that nothing maps to are black
+    KeepAll,
+    /// The largest rectangle that is fully covered by the sensor
+    LargestValid,
+}
+
+/// Filter used to sample the distorted image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resampling {
+    /// Catmull-Rom, over 4x4 pixels
+    Bicubic,
+    /// Lanczos with three lobes, over 6x6 pixels
+    Lanczos,
+}
+
+/// Brown-Conrady radial and tangential distortion around the optical centre. A corrected point
+/// at normalized coordinates `(x, y)` and `r² = x² + y²` is found in the captured image at
+/// `x * (k0 + k1 r² + k2 r⁴ + k3 r⁶) + 2 t0 x y + t1 (r² + 2 x²)`, and likewise for `y` with the
+/// tangential terms swapped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LensDistortion {
+    /// Optical centre, in pixels of the active area
+    pub center: [f32; 2],
+    /// Pixels per unit of normalized distance, along each axis
+    pub focal_length: [f32; 2],
+    pub skew: f32,
+    pub radial: [f32; 4],
+    pub tangential: [f32; 2],
+}
+
+impl LensDistortion {
+    /// From `LENS_INTRINSIC_CALIBRATION` (`[f_x, f_y, c_x, c_y, s]`) and `LENS_DISTORTION`
+    /// (`[k1, k2, k3, k4, k5]`), both relative to the active area.
+    pub fn from_camera2(intrinsics: [f32; 5], distortion: [f32; 5]) -> LensDistortion {
+        let [f_x, f_y, c_x, c_y, s] = intrinsics;
+        let [k1, k2, k3, k4, k5] = distortion;
+
+        LensDistortion {
+            center: [c_x, c_y],
+            focal_length: [f_x, f_y],
+            skew: s,
+            radial: [1.0, k1, k2, k3],
+            tangential: [k4, k5],
+        }
+    }
+
+    /// From the coefficients of one plane of a `WarpRectilinear` opcode
+    /// (`[kr0, kr1, kr2, kr3, kt0, kt1]`) and its centre, relative to an image of the given
+    /// size. Distances are normalized by the farthest corner.
+    pub fn from_warp_rectilinear(
+        coefficients: [f32; 6],
+        center: [f32; 2],
+        size: [u32; 2],
+    ) -> LensDistortion {
+        let [kr0, kr1, kr2, kr3, kt0, kt1] = coefficients;
+        let center = [center[0] * size[0] as f32, center[1] * size[1] as f32];
+
+        let reach = |center: f32, size: u32| center.max(size as f32 - center);
+        let [x, y] = [reach(center[0], size[0]), reach(center[1], size[1])];
+        let m = (x * x + y * y).sqrt();
+
+        LensDistortion {
+            center,
+            focal_length: [m, m],
+            skew: 0.0,
+            radial: [kr0, kr1, kr2, kr3],
+            tangential: [kt0, kt1],
+        }
+    }
+
+    /// The same distortion with its optical centre moved by the given offset, e.g. to make it
+    /// relative to a crop.
+    pub fn translate(&self, offset: [f32; 2]) -> LensDistortion {
+        LensDistortion {
+            center: [self.center[0] + offset[0], self.center[1] + offset[1]],
+            ..*self
+        }
+    }
+
+    fn normalize(&self, position: [f64; 2]) -> [f64; 2] {
+        let [c_x, c_y] = self.center.map(|n| n as f64);
+        let [f_x, f_y] = self.focal_length.map(|n| n as f64);
+
+        let y = (position[1] - c_y) / f_y;
+        let x = (position[0] - c_x - self.skew as f64 * y) / f_x;
+
+        [x, y]
+    }
+
+    fn denormalize(&self, [x, y]: [f64; 2]) -> [f64; 2] {
+        let [c_x, c_y] = self.center.map(|n| n as f64);
+        let [f_x, f_y] = self.focal_length.map(|n| n as f64);
+
+        [f_x * x + self.skew as f64 * y + c_x, f_y * y + c_y]
+    }
+
+    // Radial factor and tangential offset at a normalized point
+    fn terms(&self, [x, y]: [f64; 2]) -> (f64, [f64; 2]) {
+        let [k0, k1, k2, k3] = self.radial.map(|n| n as f64);
+        let [t0, t1] = self.tangential.map(|n| n as f64);
+
+        let r2 = x * x + y * y;
+        let radial = k0 + r2 * (k1 + r2 * (k2 + r2 * k3));
+        let tangential = [
+            2.0 * t0 * x * y + t1 * (r2 + 2.0 * x * x),
+            t0 * (r2 + 2.0 * y * y) + 2.0 * t1 * x * y,
+        ];
+
+        (radial, tangential)
+    }
+
+    // Distorted normalized point and the Jacobian of the distortion there
+    fn jacobian(&self, [x, y]: [f64; 2]) -> ([f64; 2], [[f64; 2]; 2]) {
+        let [_, k1, k2, k3] = self.radial.map(|n| n as f64);
+        let [t0, t1] = self.tangential.map(|n| n as f64);
+
+        let (radial, tangential) = self.terms([x, y]);
+        let r2 = x * x + y * y;
+        let slope = k1 + r2 * (2.0 * k2 + 3.0 * r2 * k3);
+
+        let distorted = [x * radial + tangential[0], y * radial + tangential[1]];
+        let jacobian = [
+            [
+                radial + 2.0 * x * x * slope + 2.0 * t0 * y + 6.0 * t1 * x,
+                2.0 * x * y * slope + 2.0 * t0 * x + 2.0 * t1 * y,
+            ],
+            [
+                2.0 * x * y * slope + 2.0 * t0 * x + 2.0 * t1 * y,
+                radial + 2.0 * y * y * slope + 6.0 * t0 * y + 2.0 * t1 * x,
+            ],
+        ];
+
+        (distorted, jacobian)
+    }
+
+    /// Position in the captured image of a position in the corrected one, in pixels.
+    pub fn distort(&self, position: [f64; 2]) -> [f64; 2] {
+        let [x, y] = self.normalize(position);
+        let (radial, tangential) = self.terms([x, y]);
+
+        self.denormalize([x * radial + tangential[0], y * radial + tangential[1]])
+    }
+
+    /// Position in the corrected image of a position in the captured one, in pixels. The model
+    /// has no closed form inverse, it is found by Newton's method. None when it does not
+    /// converge, e.g. past the radius where strong distortion folds back on itself.
+    pub fn undistort(&self, position: [f64; 2]) -> Option<[f64; 2]> {
+        const TOLERANCE: f64 = 1e-12;
+        const ITERATIONS: usize = 32;
+
+        let target = self.normalize(position);
+        let residual = |point: [f64; 2]| {
+            let (distorted, jacobian) = self.jacobian(point);
+            let error = [distorted[0] - target[0], distorted[1] - target[1]];
+            (error, jacobian, error[0].hypot(error[1]))
+        };
+
+        let mut undistorted = target;
+        let (mut error, mut jacobian, mut norm) = residual(undistorted);
+        for _ in 0..ITERATIONS {
+            if norm <= TOLERANCE {
+                break;
+            }
+
+            let [[a, b], [c, d]] = jacobian;
+            let determinant = a * d - b * c;
+            if !determinant.is_normal() {
+                return None;
+            }
+            let step = [
+                (d * error[0] - b * error[1]) / determinant,
+                (a * error[1] - c * error[0]) / determinant,
+            ];
+
+            // Halve the step until the residual goes down, a full step can overshoot where the
+            // distortion is strong
+            let mut scale = 1.0;
+            loop {
+                let next = [
+                    undistorted[0] - scale * step[0],
+                    undistorted[1] - scale * step[1],
+                ];
+                let (next_error, next_jacobian, next_norm) = residual(next);
+                if next_norm < norm {
+                    undistorted = next;
+                    (error, jacobian, norm) = (next_error, next_jacobian, next_norm);
+                    break;
+                }
+
+                scale /= 2.0;
+                if scale < 1e-6 {
+                    return None;
+                }
+            }
+        }
+
+        (norm <= TOLERANCE).then(|| self.denormalize(undistorted))
+    }
+
+    /// Scale of the corrected image around its centre that gives the requested framing, for an
+    /// image of the given size. Above one the corrected image is zoomed out.
+    pub fn zoom(&self, size: [u32; 2], crop: DistortionCrop) -> f32 {
+        let [width, height] = size.map(|n| n as f64);
+        let half = [width / 2.0, height / 2.0];
+
+        // Points along the edges of an image of the given size
+        const STEPS: usize = 64;
+        let border = (0..=STEPS).flat_map(|step| {
+            let t = step as f64 / STEPS as f64;
+            [
+                [t * width, 0.0],
+                [t * width, height],
+                [0.0, t * height],
+                [width, t * height],
+            ]
+        });
+
+        match crop {
+            // Points the model cannot bring back lie past where it folds over, there is nothing
+            // there to keep
+            DistortionCrop::KeepAll => border
+                .filter_map(|point| self.undistort(point))
+                .map(|[x, y]| ((x - half[0]).abs() / half[0]).max((y - half[1]).abs() / half[1]))
+                .fold(0.0, f64::max) as f32,
+            DistortionCrop::LargestValid => {
+                let covered = |zoom: f64| {
+                    border.clone().all(|[x, y]| {
+                        let [x, y] = self.distort([
+                            half[0] + (x - half[0]) * zoom,
+                            half[1] + (y - half[1]) * zoom,
+                        ]);
+                        (0.0..=width).contains(&x) && (0.0..=height).contains(&y)
+                    })
+                };
+
+                // Bisection, the framing shrinks as the zoom goes down
+                let mut low = 0.0;
+                let mut high = 2.0;
+                for _ in 0..32 {
+                    let zoom = (low + high) / 2.0;
+                    if covered(zoom) {
+                        low = zoom;
+                    } else {
+                        high = zoom;
+                    }
+                }
+
+                low as f32
+            }
+        }
+    }
+}
+
+/// Lateral chromatic aberration, as radial scales of red and blue relative to green. Red and blue
+/// at normalized radius `r` from the centre are taken from `k0 + k1 r² + k2 r⁴ + k3 r⁶` times as
+/// far out. Tangential terms are left out, lateral chromatic aberration is mostly radial.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChromaticAberration {
+    /// Optical centre, in pixels of the active area
+    pub center: [f32; 2],
+    /// Distance that normalizes the radius, in pixels
+    pub radius: f32,
+    pub red: [f32; 4],
+    pub blue: [f32; 4],
+}
+
+/// Where the chromatic aberration correction comes from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChromaticAberrationCorrection {
+    Manual(ChromaticAberration),
+    /// Estimated on the demosaiced image, by aligning the edges of red and blue to green
+    Auto,
+}
+
+impl ChromaticAberration {
+    /// From the red, green and blue planes of a `WarpRectilinear` opcode
+    /// (`[kr0, kr1, kr2, kr3, kt0, kt1]` each) and its centre, relative to an image of the given
+    /// size. Green keeps its own distortion, which is left to the geometric correction, red and
+    /// blue are fitted relative to it.
+    pub fn from_warp_rectilinear(
+        planes: [[f32; 6]; 3],
+        center: [f32; 2],
+        size: [u32; 2],
+    ) -> ChromaticAberration {
+        let [red, green, blue] =
+            planes.map(|plane| LensDistortion::from_warp_rectilinear(plane, center, size));
+
+        let factor = |distortion: &LensDistortion, r: f64| {
+            let (radial, _) = distortion.terms([r, 0.0]);
+            radial
+        };
+
+        // Least squares fit of the ratio to green over the radius green ends up at, in powers
+        // of r² up to r⁴
+        let fit = |plane: &LensDistortion| {
+            let mut normal = [[0.0; 3]; 3];
+            let mut target = [0.0; 3];
+
+            const STEPS: usize = 64;
+            for step in 0..=STEPS {
+                let r = step as f64 / STEPS as f64;
+                let r2 = (factor(&green, r) * r).powi(2);
+                let basis = [1.0, r2, r2 * r2];
+                let ratio = factor(plane, r) / factor(&green, r);
+
+                for i in 0..3 {
+                    for j in 0..3 {
+                        normal[i][j] += basis[i] * basis[j];
+                    }
+                    target[i] += basis[i] * ratio;
+                }
+            }
+
+            let [k0, k1, k2] = Matrix3(normal).inverse().unwrap().transform(target);
+            [k0 as f32, k1 as f32, k2 as f32, 0.0]
+        };
+
+        ChromaticAberration {
+            center: green.center,
+            radius: green.focal_length[0],
+            red: fit(&red),
+            blue: fit(&blue),
+        }
+    }
+
+    /// From the per workgroup sums of shaders/finishing_13.slang, around the given centre and
+    /// radius. Red and blue are left as they are when their edges tell nothing.
+    pub fn estimate(sums: &[f32], center: [f32; 2], radius: f32) -> ChromaticAberration {
+        // Normal equations of red and blue, for the scale and its r² term
+        let mut normal = [[0.0f64; 3]; 2];
+        let mut target = [[0.0f64; 2]; 2];
+
+        for slot in sums.chunks_exact(12) {
+            for color in 0..2 {
+                let products = &slot[color * 4..color * 4 + 4];
+                normal[color][0] += products[0] as f64;
+                normal[color][1] += products[1] as f64;
+                normal[color][2] += products[2] as f64;
+                target[color][0] += products[3] as f64;
+                target[color][1] += slot[8 + color] as f64;
+            }
+        }
+
+        // A red or blue edge found past green, by a fraction of its distance from the centre,
+        // is brought back in by the same fraction
+        let solve = |[a, b, c]: [f64; 3], [d, e]: [f64; 2]| {
+            let determinant = a * c - b * b;
+            if determinant.abs() <= f64::EPSILON * a * c {
+                return [1.0, 0.0, 0.0, 0.0];
+            }
+
+            let scale = (c * d - b * e) / determinant;
+            let quadratic = (a * e - b * d) / determinant;
+            [1.0 - scale as f32, -quadratic as f32, 0.0, 0.0]
+        };
+
+        ChromaticAberration {
+            center,
+            radius,
+            red: solve(normal[0], target[0]),
+            blue: solve(normal[1], target[1]),
+        }
+    }
+
+    /// The same correction with its centre moved by the given offset, e.g. to make it relative
+    /// to a crop.
+    pub fn translate(&self, offset: [f32; 2]) -> ChromaticAberration {
+        ChromaticAberration {
+            center: [self.center[0] + offset[0], self.center[1] + offset[1]],
+            ..*self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: [u32; 2] = [4032, 3024];
+
+    fn distortion(coefficients: [f32; 6]) -> LensDistortion {
+        LensDistortion::from_warp_rectilinear(coefficients, [0.52, 0.47], SIZE)
+    }
+
+    #[test]
+    fn undistort_inverts_distort() {
+        let distortions = [
+            // Barrel, pincushion, and pincushion strong enough that 20 rounds of fixed point
+            // iteration are still pixels off in the corners
+            [1.0, -0.3, 0.1, 0.0, 0.002, -0.001],
+            [1.0, 0.2, 0.05, 0.01, -0.003, 0.002],
+            [1.0, 0.8, 0.4, 0.2, 0.01, -0.01],
+        ];
+
+        let [width, height] = SIZE.map(|n| n as f64);
+        let points = [
+            [0.0, 0.0],
+            [width, 0.0],
+            [0.0, height],
+            [width, height],
+            [width / 2.0, height / 2.0],
+            [width / 3.0, height * 0.9],
+        ];
+
+        for coefficients in distortions {
+            let distortion = distortion(coefficients);
+            for point in points {
+                let undistorted = distortion.undistort(point).unwrap();
+                let [x, y] = distortion.distort(undistorted);
+                assert!(
+                    (x - point[0]).abs() < 1e-6 && (y - point[1]).abs() < 1e-6,
+                    "{coefficients:?} {point:?} {undistorted:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn undistort_past_the_fold() {
+        // r (1 - 0.6 r²) peaks just under 0.5, nothing in the corrected image lands further out
+        let distortion = distortion([1.0, -0.6, 0.0, 0.0, 0.0, 0.0]);
+        let [c_x, c_y] = distortion.center.map(|n| n as f64);
+        let radius = distortion.focal_length[0] as f64;
+
+        assert!(distortion.undistort([c_x + 0.45 * radius, c_y]).is_some());
+        assert!(distortion.undistort([c_x + 0.6 * radius, c_y]).is_none());
+    }
+}
@@ -1,4 +1,4 @@
-use std::slice;
+use std::sync::Arc;
 
 use vulkano::{
     DeviceSize,
@@ -8,225 +8,209 @@ use vulkano::{
         PrimaryAutoCommandBuffer, PrimaryCommandBufferAbstract,
     },
     descriptor_set::{DescriptorSet, WriteDescriptorSet},
-    format::Format,
-    image::{Image, ImageCreateInfo, ImageUsage, view::ImageView},
+    format::{Format, FormatFeatures},
+    image::{Image, ImageCreateInfo, ImageType, ImageUsage, view::ImageView},
     memory::allocator::{AllocationCreateInfo, MemoryTypeFilter},
-    pipeline::{
-        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
-        PipelineShaderStageCreateInfo, compute::ComputePipelineCreateInfo,
-        layout::PipelineDescriptorSetLayoutCreateInfo,
-    },
+    pipeline::{Pipeline, PipelineBindPoint},
     sync::{self, GpuFuture},
 };
 
 use crate::pipeline::{
+    adjustment::{self, ColorAdjustment},
+    awb::{self, AwbStatistics, WhiteBalance},
+    cfa::CfaPattern,
+    color::{
+        self, ChromaticAdaptation, ColorProfile, ColorSpace, GamutCompression, Matrix3,
+        TransferFunction,
+    },
     context,
-    stage::{StageInPipeline, StageOutput, StageResources},
+    distortion::{ChromaticAberration, ChromaticAberrationCorrection, LensDistortion, Resampling},
+    lut::{Look, Lut, LutInterpolation, LutKind, LutSpace},
+    parameters::{GainMap, Parameters, Rect},
+    pyramid::Pyramid,
+    stage::{self, Pass, StageInPipeline, StageOutput, StageResources},
+    tone::{self, Exposure, ToneCurve, ToneMapping},
 };
 
-struct Stage0 {
-    color_filter_arrangement: i32,
-
-    // Bayer raw image buffer
-    buffer: *const u8,
-    buffer_len: usize,
-
-    extent: [u32; 3],
-}
-
 struct Stage1 {
+    // Top-left corner of the output in the raw image
+    raw_offset: [u32; 2],
+
     color_gains: [f32; 4],
 
-    black_level: [i32; 4],
+    black_level: [f32; 4],
     white_level: i32,
 
+    // Spans the active area
+    lens_shading: Arc<ImageView>,
+    lens_shading_scale: [f32; 2],
+
+    cfa_pattern: CfaPattern,
+    cfa_origin: [u32; 2],
+
     extent: [u32; 3],
 }
 
 struct Stage2 {
+    // Position of the first red site
+    first_red: [u32; 2],
+
     extent: [u32; 3],
 }
 
 struct Stage3 {
-    forward_matrix_1: [f32; 9],
-    forward_matrix_2: [f32; 9],
+    // White balanced camera RGB to linear output RGB
+    camera_to_output: [f32; 9],
 }
 
-struct Stage4 {}
+struct Stage4 {
+    transfer_function: TransferFunction,
+    // In place of the transfer function
+    tone_curve: Option<ToneCurve>,
+}
 
 struct Stage5 {
+    format: Format,
+
     extent: [u32; 3],
 }
 
-impl StageInPipeline for Stage0 {
-    fn create_stage_resources(
-        &self,
-        context: &context::Context,
-        _: Option<StageOutput>,
-    ) -> StageResources {
-        let (_, raw_image_view) = {
-            let buffer = Buffer::new_slice::<u8>(
-                context.memory_allocator.clone(),
-                BufferCreateInfo {
-                    usage: BufferUsage::TRANSFER_SRC,
-                    ..Default::default()
-                },
-                AllocationCreateInfo {
-                    memory_type_filter: MemoryTypeFilter::PREFER_HOST
-                        | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
-                    ..Default::default()
-                },
-                self.buffer_len as DeviceSize,
-            )
-            .unwrap();
+struct Stage6 {
+    cfa_pattern: CfaPattern,
+    cfa_origin: [u32; 2],
 
-            // Lock subbufer and copy the entire RAW data into it
-            buffer
-                .write()
-                .expect("Failed to lock subbufer for writing")
-                .copy_from_slice(unsafe {
-                    slice::from_raw_parts(self.buffer, self.buffer_len as usize)
-                });
+    extent: [u32; 3],
+}
 
-            let image = Image::new(
-                context.memory_allocator.clone(),
-                ImageCreateInfo {
-                    format: Format::R16_UINT,
-                    extent: self.extent,
-                    usage: ImageUsage::STORAGE | ImageUsage::TRANSFER_DST,
-                    ..Default::default()
-                },
-                AllocationCreateInfo::default(),
-            )
-            .unwrap();
+struct Stage7 {
+    cfa_pattern: CfaPattern,
+    cfa_origin: [u32; 2],
+}
 
-            let view = ImageView::new_default(image.clone()).unwrap();
+struct Stage8 {
+    extent: [u32; 3],
+}
 
-            let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
-                context.command_buffer_allocator.clone(),
-                context.queue.queue_family_index(),
-                CommandBufferUsage::OneTimeSubmit,
-            )
-            .unwrap();
+struct Stage10 {
+    color_gains: [f32; 4],
+    // Spans the active area, where it raised the white level
+    lens_shading: Arc<ImageView>,
+    lens_shading_scale: [f32; 2],
 
-            command_buffer_builder
-                .copy_buffer_to_image(CopyBufferToImageInfo::buffer_image(buffer, image.clone()))
-                .unwrap();
+    cfa_pattern: CfaPattern,
+    cfa_origin: [u32; 2],
 
-            let command_buffer = command_buffer_builder.build().unwrap();
+    mode: HighlightMode,
+    // Clipped colours of each pixel, written when present
+    mask: Option<Subbuffer<[u32]>>,
 
-            command_buffer
-                .execute(context.queue.clone())
-                .unwrap()
-                .then_signal_fence_and_flush()
-                .unwrap()
-                .wait(None)
-                .unwrap();
+    extent: [u32; 3],
+}
 
-            (image, view)
-        };
+struct Stage11 {
+    cfa_pattern: CfaPattern,
+    cfa_origin: [u32; 2],
 
-        let (_, raw_shifted_image_view) = {
-            let image = Image::new(
-                context.memory_allocator.clone(),
-                ImageCreateInfo {
-                    format: Format::R16_UINT,
-                    extent: self.extent,
-                    usage: ImageUsage::STORAGE,
-                    ..Default::default()
-                },
-                AllocationCreateInfo::default(),
-            )
-            .unwrap();
+    // Detection against the neighbours of the same colour, off when missing
+    threshold: Option<f32>,
+    // Known defects, relative to the output
+    defects: Vec<Rect>,
 
-            let view = ImageView::new_default(image.clone()).unwrap();
+    extent: [u32; 3],
+}
 
-            (image, view)
-        };
+struct Stage12 {
+    // Relative to this image
+    distortion: LensDistortion,
+    zoom: f32,
+    resampling: Resampling,
+    extent: [u32; 3],
+}
 
-        mod cs {
-            vulkano_shaders::shader! {
-                bytes: "shaders/finishing_0.spv"
-            }
-        }
+struct Stage13 {
+    // Relative to this image
+    center: [f32; 2],
+    radius: f32,
+    extent: [u32; 3],
+}
 
-        let compute_shader = cs::load(context.device.clone()).unwrap();
-        let stage = PipelineShaderStageCreateInfo::new(compute_shader.entry_point("main").unwrap());
-        let layout = PipelineLayout::new(
-            context.device.clone(),
-            PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
-                .into_pipeline_layout_create_info(context.device.clone())
-                .unwrap(),
-        )
-        .unwrap();
+struct Stage14 {
+    // Relative to this image
+    aberration: ChromaticAberration,
+    extent: [u32; 3],
+}
 
-        let compute_pipeline = ComputePipeline::new(
-            context.device.clone(),
-            None,
-            ComputePipelineCreateInfo::stage_layout(stage, layout),
-        )
-        .expect("Failed to create compute pipeline");
+struct Stage15 {
+    tone_mapping: ToneMapping,
+    aces_input: [f32; 9],
+    aces_output: [f32; 9],
+}
 
-        let layout = compute_pipeline.layout().set_layouts().get(0).unwrap();
-        let descriptor_set = DescriptorSet::new(
-            context.descriptor_set_allocator.clone(),
-            layout.clone(),
-            [
-                WriteDescriptorSet::image_view(0, raw_image_view.clone()),
-                WriteDescriptorSet::image_view(1, raw_shifted_image_view.clone()),
-            ],
-            [],
-        )
-        .unwrap();
+struct Stage16 {
+    compression: f32,
+    // Luminance of each output primary
+    luminance: [f32; 3],
+    exposures: Pyramid,
+    fused: Pyramid,
+    // Between preparing the exposures and applying the fused one
+    passes: Vec<Pass>,
+    extent: [u32; 3],
+}
 
-        StageResources {
-            compute_pipeline,
-            descriptor_set,
-            image_views: vec![raw_image_view, raw_shifted_image_view],
-            buffers: vec![],
-            commands: vec![],
-        }
-    }
+struct Stage20 {
+    // Camera RGB to output luminance
+    luminance: [f32; 3],
+    cfa_pattern: CfaPattern,
+    cfa_origin: [u32; 2],
+}
 
-    fn bind_stage_pipeline_and_dispatch(
-        &self,
-        command_buffer_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
-        resources: &StageResources,
-        work_groups: [u32; 3],
-    ) {
-        #[derive(BufferContents)]
-        #[repr(C)]
-        struct Constants {
-            shift_vector: [i32; 2],
-        }
+struct Stage21 {
+    look: Look,
+}
 
-        let shift_vector = match self.color_filter_arrangement {
-            0 /* RGGB */ => [0, 0],
-            1 /* GRBG */ => [1, 0],
-            2 /* GBRG */ => [0, 1],
-            3 /* BGGR */ => [1, 1],
-            _ => [0, 0],
-        };
+struct Stage22 {
+    adjustment: ColorAdjustment,
+    rgb_to_lms: [f32; 9],
+    lms_to_rgb: [f32; 9],
+}
 
-        let constants = Constants { shift_vector };
+struct Stage23 {
+    gamut_compression: GamutCompression,
+}
 
-        command_buffer_builder
-            .bind_pipeline_compute(resources.compute_pipeline.clone())
-            .unwrap()
-            .push_constants(resources.compute_pipeline.layout().clone(), 0, constants)
-            .unwrap()
-            .bind_descriptor_sets(
-                PipelineBindPoint::Compute,
-                resources.compute_pipeline.layout().clone(),
-                0,
-                resources.descriptor_set.clone(),
-            )
-            .unwrap();
+struct Stage9 {
+    raw_offset: [u32; 2],
 
-        unsafe {
-            command_buffer_builder.dispatch(work_groups).unwrap();
-        }
-    }
+    black_level: [f32; 4],
+    white_level: i32,
+
+    // Spans the active area
+    lens_shading: Arc<ImageView>,
+    lens_shading_scale: [f32; 2],
+
+    cfa_pattern: CfaPattern,
+    cfa_origin: [u32; 2],
+
+    extent: [u32; 3],
+}
+
+/// Treatment of sites that reached the white level.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HighlightMode {
+    /// Clips every channel at the level of the first one to clip, highlights stay neutral
+    Clip,
+    /// Lifts clipped sites to the brightest colour around them, fading highlights into white
+    Blend,
+    /// Propagates the chromaticity of nearby unclipped areas into clipped ones, blending where
+    /// every colour clipped
+    Reconstruct,
+}
+
+/// Bit depth of each channel of the quantized output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitDepth {
+    Eight,
+    Sixteen,
 }
 
 impl StageInPipeline for Stage1 {
@@ -259,22 +243,8 @@ impl StageInPipeline for Stage1 {
             }
         }
 
-        let compute_shader = cs::load(context.device.clone()).unwrap();
-        let stage = PipelineShaderStageCreateInfo::new(compute_shader.entry_point("main").unwrap());
-        let layout = PipelineLayout::new(
-            context.device.clone(),
-            PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
-                .into_pipeline_layout_create_info(context.device.clone())
-                .unwrap(),
-        )
-        .unwrap();
-
-        let compute_pipeline = ComputePipeline::new(
-            context.device.clone(),
-            None,
-            ComputePipelineCreateInfo::stage_layout(stage, layout),
-        )
-        .expect("Failed to create compute pipeline");
+        let compute_pipeline =
+            stage::create_compute_pipeline(context, cs::load(context.device.clone()).unwrap());
 
         let layout = compute_pipeline.layout().set_layouts().get(0).unwrap();
         let descriptor_set = DescriptorSet::new(
@@ -283,9 +253,10 @@ impl StageInPipeline for Stage1 {
             [
                 WriteDescriptorSet::image_view(
                     0,
-                    input.unwrap().image_views.get(1).unwrap().clone(),
+                    input.unwrap().image_views.get(0).unwrap().clone(),
                 ),
                 WriteDescriptorSet::image_view(1, raw_normalized_image_view.clone()),
+                WriteDescriptorSet::image_view(2, self.lens_shading.clone()),
             ],
             [],
         )
@@ -310,14 +281,24 @@ impl StageInPipeline for Stage1 {
         #[repr(C)]
         struct Constants {
             color_gains: [f32; 4],
-            black_level: [i32; 4],
+            black_level: [f32; 4],
+            cfa_pattern: [u32; 4],
+            cfa_size: [u32; 2],
+            cfa_origin: [u32; 2],
+            raw_offset: [u32; 2],
+            lens_shading_scale: [f32; 2],
             white_level: i32,
         }
 
         let constants = Constants {
             color_gains: self.color_gains,
-            white_level: self.white_level,
             black_level: self.black_level,
+            cfa_pattern: self.cfa_pattern.packed(),
+            cfa_size: self.cfa_pattern.size(),
+            cfa_origin: self.cfa_origin,
+            raw_offset: self.raw_offset,
+            lens_shading_scale: self.lens_shading_scale,
+            white_level: self.white_level,
         };
 
         command_buffer_builder
@@ -369,22 +350,8 @@ impl StageInPipeline for Stage2 {
             }
         }
 
-        let compute_shader = cs::load(context.device.clone()).unwrap();
-        let stage = PipelineShaderStageCreateInfo::new(compute_shader.entry_point("main").unwrap());
-        let layout = PipelineLayout::new(
-            context.device.clone(),
-            PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
-                .into_pipeline_layout_create_info(context.device.clone())
-                .unwrap(),
-        )
-        .unwrap();
-
-        let compute_pipeline = ComputePipeline::new(
-            context.device.clone(),
-            None,
-            ComputePipelineCreateInfo::stage_layout(stage, layout),
-        )
-        .expect("Failed to create compute pipeline");
+        let compute_pipeline =
+            stage::create_compute_pipeline(context, cs::load(context.device.clone()).unwrap());
 
         let layout = compute_pipeline.layout().set_layouts().get(0).unwrap();
         let descriptor_set = DescriptorSet::new(
@@ -419,11 +386,11 @@ impl StageInPipeline for Stage2 {
         #[derive(BufferContents)]
         #[repr(C)]
         struct Constants {
-            size: [i32; 2],
+            first_red: [u32; 2],
         }
 
         let constants = Constants {
-            size: [self.extent[0] as i32, self.extent[1] as i32],
+            first_red: self.first_red,
         };
 
         command_buffer_builder
@@ -457,22 +424,8 @@ impl StageInPipeline for Stage3 {
             }
         }
 
-        let compute_shader = cs::load(context.device.clone()).unwrap();
-        let stage = PipelineShaderStageCreateInfo::new(compute_shader.entry_point("main").unwrap());
-        let layout = PipelineLayout::new(
-            context.device.clone(),
-            PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
-                .into_pipeline_layout_create_info(context.device.clone())
-                .unwrap(),
-        )
-        .unwrap();
-
-        let compute_pipeline = ComputePipeline::new(
-            context.device.clone(),
-            None,
-            ComputePipelineCreateInfo::stage_layout(stage, layout),
-        )
-        .expect("Failed to create compute pipeline");
+        let compute_pipeline =
+            stage::create_compute_pipeline(context, cs::load(context.device.clone()).unwrap());
 
         let layout = compute_pipeline.layout().set_layouts().get(0).unwrap();
         let descriptor_set = DescriptorSet::new(
@@ -504,48 +457,27 @@ impl StageInPipeline for Stage3 {
         #[derive(BufferContents)]
         #[repr(C)]
         struct Constants {
-            forward_matrix_1: [[f32; 4]; 3],
-            forward_matrix_2: [[f32; 4]; 3],
+            camera_to_output: [[f32; 4]; 3],
         }
 
         let constants = Constants {
-            forward_matrix_1: [
-                [
-                    self.forward_matrix_1[0],
-                    self.forward_matrix_1[1],
-                    self.forward_matrix_1[2],
-                    0.0, /* padding */
-                ],
+            camera_to_output: [
                 [
-                    self.forward_matrix_1[3],
-                    self.forward_matrix_1[4],
-                    self.forward_matrix_1[5],
+                    self.camera_to_output[0],
+                    self.camera_to_output[1],
+                    self.camera_to_output[2],
                     0.0, /* padding */
                 ],
                 [
-                    self.forward_matrix_1[6],
-                    self.forward_matrix_1[7],
-                    self.forward_matrix_1[8],
+                    self.camera_to_output[3],
+                    self.camera_to_output[4],
+                    self.camera_to_output[5],
                     0.0, /* padding */
                 ],
-            ],
-            forward_matrix_2: [
-                [
-                    self.forward_matrix_2[0],
-                    self.forward_matrix_2[1],
-                    self.forward_matrix_2[2],
-                    0.0, /* padding2*/
-                ],
-                [
-                    self.forward_matrix_2[3],
-                    self.forward_matrix_2[4],
-                    self.forward_matrix_2[5],
-                    0.0, /* padding2*/
-                ],
                 [
-                    self.forward_matrix_2[6],
-                    self.forward_matrix_2[7],
-                    self.forward_matrix_2[8],
+                    self.camera_to_output[6],
+                    self.camera_to_output[7],
+                    self.camera_to_output[8],
                     0.0, /* padding */
                 ],
             ],
@@ -576,37 +508,45 @@ impl StageInPipeline for Stage4 {
         context: &context::Context,
         input: Option<StageOutput>,
     ) -> StageResources {
+        // Zero points stand in for the curve when the transfer function applies
+        let curve_buffer = Buffer::from_iter(
+            context.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                    | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+                ..Default::default()
+            },
+            match &self.tone_curve {
+                Some(tone_curve) => tone_curve.points(),
+                None => vec![[0.0; 2]; 3 * tone::MAX_TONE_CURVE_POINTS],
+            },
+        )
+        .unwrap();
+
         mod cs {
             vulkano_shaders::shader! {
                 bytes: "shaders/finishing_4.spv"
             }
         }
 
-        let compute_shader = cs::load(context.device.clone()).unwrap();
-        let stage = PipelineShaderStageCreateInfo::new(compute_shader.entry_point("main").unwrap());
-        let layout = PipelineLayout::new(
-            context.device.clone(),
-            PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
-                .into_pipeline_layout_create_info(context.device.clone())
-                .unwrap(),
-        )
-        .unwrap();
-
-        let compute_pipeline = ComputePipeline::new(
-            context.device.clone(),
-            None,
-            ComputePipelineCreateInfo::stage_layout(stage, layout),
-        )
-        .expect("Failed to create compute pipeline");
+        let compute_pipeline =
+            stage::create_compute_pipeline(context, cs::load(context.device.clone()).unwrap());
 
         let layout = compute_pipeline.layout().set_layouts().get(0).unwrap();
         let descriptor_set = DescriptorSet::new(
             context.descriptor_set_allocator.clone(),
             layout.clone(),
-            [WriteDescriptorSet::image_view(
-                0,
-                input.as_ref().unwrap().image_views.get(0).unwrap().clone(),
-            )],
+            [
+                WriteDescriptorSet::image_view(
+                    0,
+                    input.as_ref().unwrap().image_views.get(0).unwrap().clone(),
+                ),
+                WriteDescriptorSet::buffer(1, curve_buffer),
+            ],
             [],
         )
         .unwrap();
@@ -626,9 +566,40 @@ impl StageInPipeline for Stage4 {
         resources: &StageResources,
         work_groups: [u32; 3],
     ) {
+        #[derive(BufferContents)]
+        #[repr(C)]
+        struct Constants {
+            linear_slope: f32,
+            linear_cutoff: f32,
+            scale: f32,
+            offset: f32,
+            exponent: f32,
+            red_length: u32,
+            green_length: u32,
+            blue_length: u32,
+        }
+
+        let [red_length, green_length, blue_length] = self
+            .tone_curve
+            .as_ref()
+            .map_or([0; 3], |tone_curve| tone_curve.lengths());
+
+        let constants = Constants {
+            linear_slope: self.transfer_function.linear_slope,
+            linear_cutoff: self.transfer_function.linear_cutoff,
+            scale: self.transfer_function.scale,
+            offset: self.transfer_function.offset,
+            exponent: self.transfer_function.exponent,
+            red_length,
+            green_length,
+            blue_length,
+        };
+
         command_buffer_builder
             .bind_pipeline_compute(resources.compute_pipeline.clone())
             .unwrap()
+            .push_constants(resources.compute_pipeline.layout().clone(), 0, constants)
+            .unwrap()
             .bind_descriptor_sets(
                 PipelineBindPoint::Compute,
                 resources.compute_pipeline.layout().clone(),
@@ -653,7 +624,7 @@ impl StageInPipeline for Stage5 {
             let image = Image::new(
                 context.memory_allocator.clone(),
                 ImageCreateInfo {
-                    format: Format::R8G8B8A8_UNORM,
+                    format: self.format,
                     extent: self.extent,
                     usage: ImageUsage::STORAGE | ImageUsage::TRANSFER_SRC,
                     ..Default::default()
@@ -675,7 +646,7 @@ impl StageInPipeline for Stage5 {
                         | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
                     ..Default::default()
                 },
-                (0..self.extent[0] * self.extent[1] * 4).map(|_| 0u8),
+                (0..self.extent[0] * self.extent[1] * self.format.block_size() as u32).map(|_| 0u8),
             )
             .unwrap();
 
@@ -704,22 +675,8 @@ impl StageInPipeline for Stage5 {
             }
         }
 
-        let compute_shader = cs::load(context.device.clone()).unwrap();
-        let stage = PipelineShaderStageCreateInfo::new(compute_shader.entry_point("main").unwrap());
-        let layout = PipelineLayout::new(
-            context.device.clone(),
-            PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
-                .into_pipeline_layout_create_info(context.device.clone())
-                .unwrap(),
-        )
-        .unwrap();
-
-        let compute_pipeline = ComputePipeline::new(
-            context.device.clone(),
-            None,
-            ComputePipelineCreateInfo::stage_layout(stage, layout),
-        )
-        .expect("Failed to create compute pipeline");
+        let compute_pipeline =
+            stage::create_compute_pipeline(context, cs::load(context.device.clone()).unwrap());
 
         let layout = compute_pipeline.layout().set_layouts().get(0).unwrap();
         let descriptor_set = DescriptorSet::new(
@@ -768,121 +725,2576 @@ impl StageInPipeline for Stage5 {
     }
 }
 
-pub struct Finish {
-    output: Option<Subbuffer<[u8]>>,
-}
-
-impl Finish {
-    pub fn new() -> Finish {
-        Finish { output: None }
-    }
-
-    pub fn finish(
-        &mut self,
+impl StageInPipeline for Stage6 {
+    fn create_stage_resources(
+        &self,
         context: &context::Context,
-        buffer: *const u8,
-        buffer_len: usize,
-        size: [i32; 2],
-        color_filter_arrangement: i32,
-        white_level: i32,
-        black_level: [i32; 4],
-        color_gains: [f32; 4],
-        forward_matrix_1: [f32; 9],
-        forward_matrix_2: [f32; 9],
-    ) {
-        let extent = [size[0] as u32, size[1] as u32, 1];
-
-        // Shift Bayer color filter arrangement to match RGGB mosaic pattern
-        let stage0 = Stage0 {
-            color_filter_arrangement,
-            buffer,
-            buffer_len,
-            extent,
-        };
-
-        // Black level subtraction, white balancing and normalization
-        let stage1 = Stage1 {
-            color_gains,
-            black_level,
-            white_level,
-            extent,
-        };
+        input: Option<StageOutput>,
+    ) -> StageResources {
+        let (_, rgba_image_view) = {
+            let image = Image::new(
+                context.memory_allocator.clone(),
+                ImageCreateInfo {
+                    format: Format::R16G16B16A16_SFLOAT,
+                    extent: self.extent,
+                    usage: ImageUsage::STORAGE,
+                    ..Default::default()
+                },
+                AllocationCreateInfo::default(),
+            )
+            .unwrap();
 
-        // Demosaicing
-        let stage2 = Stage2 { extent };
+            let view = ImageView::new_default(image.clone()).unwrap();
 
-        // Color correction (sensor color space to CIE XYZ and then to linear sRGB)
-        let stage3 = Stage3 {
-            forward_matrix_1,
-            forward_matrix_2,
+            (image, view)
         };
 
-        // Gamma correction
-        let stage4 = Stage4 {};
-
-        // Quantization
-        let stage5 = Stage5 { extent };
-
-        let stages: Vec<&dyn StageInPipeline> =
-            vec![&stage0, &stage1, &stage2, &stage3, &stage4, &stage5];
-
-        let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
-            context.command_buffer_allocator.clone(),
-            context.queue.queue_family_index(),
-            CommandBufferUsage::OneTimeSubmit,
-        )
-        .unwrap();
-
-        let work_groups = {
-            let w = extent[0];
-            let h = extent[1];
-            // Rounding up
-            [(w + 7) / 8, (h + 7) / 8, 1]
-        };
+        mod cs {
+            vulkano_shaders::shader! {
+                bytes: "shaders/finishing_6.spv"
+            }
+        }
 
-        let mut stage_output: Option<StageOutput> = None;
+        let compute_pipeline =
+            stage::create_compute_pipeline(context, cs::load(context.device.clone()).unwrap());
 
-        for stage in stages {
-            let resources = stage.create_stage_resources(&context, stage_output);
-            stage.bind_stage_pipeline_and_dispatch(
-                &mut command_buffer_builder,
-                &resources,
-                work_groups,
-            );
-            stage_output = Some(StageOutput {
-                image_views: resources.image_views,
-                buffers: resources.buffers,
-                commands: resources.commands,
+        let layout = compute_pipeline.layout().set_layouts().get(0).unwrap();
+        let descriptor_set = DescriptorSet::new(
+            context.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::image_view(
+                    0,
+                    input.unwrap().image_views.get(0).unwrap().clone(),
+                ),
+                WriteDescriptorSet::image_view(1, rgba_image_view.clone()),
+            ],
+            [],
+        )
+        .unwrap();
+
+        StageResources {
+            compute_pipeline,
+            descriptor_set,
+            image_views: vec![rgba_image_view],
+            buffers: vec![],
+            commands: vec![],
+        }
+    }
+
+    fn bind_stage_pipeline_and_dispatch(
+        &self,
+        command_buffer_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        resources: &StageResources,
+        work_groups: [u32; 3],
+    ) {
+        #[derive(BufferContents)]
+        #[repr(C)]
+        struct Constants {
+            cfa_pattern: [u32; 4],
+            cfa_size: [u32; 2],
+            cfa_origin: [u32; 2],
+        }
+
+        let constants = Constants {
+            cfa_pattern: self.cfa_pattern.packed(),
+            cfa_size: self.cfa_pattern.size(),
+            cfa_origin: self.cfa_origin,
+        };
+
+        command_buffer_builder
+            .bind_pipeline_compute(resources.compute_pipeline.clone())
+            .unwrap()
+            .push_constants(resources.compute_pipeline.layout().clone(), 0, constants)
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                resources.compute_pipeline.layout().clone(),
+                0,
+                resources.descriptor_set.clone(),
+            )
+            .unwrap();
+
+        unsafe {
+            command_buffer_builder.dispatch(work_groups).unwrap();
+        }
+    }
+}
+
+impl StageInPipeline for Stage7 {
+    fn create_stage_resources(
+        &self,
+        context: &context::Context,
+        input: Option<StageOutput>,
+    ) -> StageResources {
+        mod cs {
+            vulkano_shaders::shader! {
+                bytes: "shaders/finishing_7.spv"
+            }
+        }
+
+        let compute_pipeline =
+            stage::create_compute_pipeline(context, cs::load(context.device.clone()).unwrap());
+
+        let layout = compute_pipeline.layout().set_layouts().get(0).unwrap();
+        let descriptor_set = DescriptorSet::new(
+            context.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [WriteDescriptorSet::image_view(
+                0,
+                input.as_ref().unwrap().image_views.get(0).unwrap().clone(),
+            )],
+            [],
+        )
+        .unwrap();
+
+        StageResources {
+            compute_pipeline,
+            descriptor_set,
+            image_views: input.unwrap().image_views,
+            buffers: vec![],
+            commands: vec![],
+        }
+    }
+
+    fn bind_stage_pipeline_and_dispatch(
+        &self,
+        command_buffer_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        resources: &StageResources,
+        work_groups: [u32; 3],
+    ) {
+        #[derive(BufferContents)]
+        #[repr(C)]
+        struct Constants {
+            cfa_pattern: [u32; 4],
+            cfa_size: [u32; 2],
+            cfa_origin: [u32; 2],
+        }
+
+        let constants = Constants {
+            cfa_pattern: self.cfa_pattern.packed(),
+            cfa_size: self.cfa_pattern.size(),
+            cfa_origin: self.cfa_origin,
+        };
+
+        command_buffer_builder
+            .bind_pipeline_compute(resources.compute_pipeline.clone())
+            .unwrap()
+            .push_constants(resources.compute_pipeline.layout().clone(), 0, constants)
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                resources.compute_pipeline.layout().clone(),
+                0,
+                resources.descriptor_set.clone(),
+            )
+            .unwrap();
+
+        unsafe {
+            command_buffer_builder.dispatch(work_groups).unwrap();
+        }
+    }
+}
+
+impl StageInPipeline for Stage8 {
+    fn create_stage_resources(
+        &self,
+        context: &context::Context,
+        input: Option<StageOutput>,
+    ) -> StageResources {
+        let (_, rgba_image_view) = {
+            let image = Image::new(
+                context.memory_allocator.clone(),
+                ImageCreateInfo {
+                    format: Format::R16G16B16A16_SFLOAT,
+                    extent: self.extent,
+                    usage: ImageUsage::STORAGE,
+                    ..Default::default()
+                },
+                AllocationCreateInfo::default(),
+            )
+            .unwrap();
+
+            let view = ImageView::new_default(image.clone()).unwrap();
+
+            (image, view)
+        };
+
+        mod cs {
+            vulkano_shaders::shader! {
+                bytes: "shaders/finishing_8.spv"
+            }
+        }
+
+        let compute_pipeline =
+            stage::create_compute_pipeline(context, cs::load(context.device.clone()).unwrap());
+
+        let layout = compute_pipeline.layout().set_layouts().get(0).unwrap();
+        let descriptor_set = DescriptorSet::new(
+            context.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::image_view(
+                    0,
+                    input.unwrap().image_views.get(0).unwrap().clone(),
+                ),
+                WriteDescriptorSet::image_view(1, rgba_image_view.clone()),
+            ],
+            [],
+        )
+        .unwrap();
+
+        StageResources {
+            compute_pipeline,
+            descriptor_set,
+            image_views: vec![rgba_image_view],
+            buffers: vec![],
+            commands: vec![],
+        }
+    }
+
+    fn bind_stage_pipeline_and_dispatch(
+        &self,
+        command_buffer_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        resources: &StageResources,
+        work_groups: [u32; 3],
+    ) {
+        command_buffer_builder
+            .bind_pipeline_compute(resources.compute_pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                resources.compute_pipeline.layout().clone(),
+                0,
+                resources.descriptor_set.clone(),
+            )
+            .unwrap();
+
+        unsafe {
+            command_buffer_builder.dispatch(work_groups).unwrap();
+        }
+    }
+}
+
+impl StageInPipeline for Stage9 {
+    fn create_stage_resources(
+        &self,
+        context: &context::Context,
+        input: Option<StageOutput>,
+    ) -> StageResources {
+        let work_groups = stage::work_group_count(self.extent, context.work_group_size);
+
+        // Sum, gradient sum and count of each CFA channel per workgroup, and the histogram
+        let (sums_buffer, histogram_buffer) = {
+            let allocation_info = || AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                    | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                ..Default::default()
+            };
+
+            let sums = Buffer::from_iter(
+                context.memory_allocator.clone(),
+                BufferCreateInfo {
+                    usage: BufferUsage::STORAGE_BUFFER,
+                    ..Default::default()
+                },
+                allocation_info(),
+                (0..work_groups[0] * work_groups[1] * 12).map(|_| 0f32),
+            )
+            .unwrap();
+
+            let histogram = Buffer::from_iter(
+                context.memory_allocator.clone(),
+                BufferCreateInfo {
+                    usage: BufferUsage::STORAGE_BUFFER,
+                    ..Default::default()
+                },
+                allocation_info(),
+                (0..4 * awb::HISTOGRAM_BINS as u32).map(|_| 0u32),
+            )
+            .unwrap();
+
+            (sums, histogram)
+        };
+
+        mod cs {
+            vulkano_shaders::shader! {
+                bytes: "shaders/finishing_9.spv"
+            }
+        }
+
+        let compute_pipeline =
+            stage::create_compute_pipeline(context, cs::load(context.device.clone()).unwrap());
+
+        let layout = compute_pipeline.layout().set_layouts().get(0).unwrap();
+        let descriptor_set = DescriptorSet::new(
+            context.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::image_view(
+                    0,
+                    input.as_ref().unwrap().image_views.get(0).unwrap().clone(),
+                ),
+                WriteDescriptorSet::buffer(1, sums_buffer.clone()),
+                WriteDescriptorSet::buffer(2, histogram_buffer.clone()),
+                WriteDescriptorSet::image_view(3, self.lens_shading.clone()),
+            ],
+            [],
+        )
+        .unwrap();
+
+        // The raw image passes through, the statistics are read back by the host
+        StageResources {
+            compute_pipeline,
+            descriptor_set,
+            image_views: input.unwrap().image_views,
+            buffers: vec![sums_buffer.into_bytes(), histogram_buffer.into_bytes()],
+            commands: vec![],
+        }
+    }
+
+    fn bind_stage_pipeline_and_dispatch(
+        &self,
+        command_buffer_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        resources: &StageResources,
+        work_groups: [u32; 3],
+    ) {
+        #[derive(BufferContents)]
+        #[repr(C)]
+        struct Constants {
+            black_level: [f32; 4],
+            cfa_pattern: [u32; 4],
+            cfa_size: [u32; 2],
+            cfa_origin: [u32; 2],
+            raw_offset: [u32; 2],
+            size: [i32; 2],
+            lens_shading_scale: [f32; 2],
+            white_level: i32,
+        }
+
+        let constants = Constants {
+            black_level: self.black_level,
+            cfa_pattern: self.cfa_pattern.packed(),
+            cfa_size: self.cfa_pattern.size(),
+            cfa_origin: self.cfa_origin,
+            raw_offset: self.raw_offset,
+            size: [self.extent[0] as i32, self.extent[1] as i32],
+            lens_shading_scale: self.lens_shading_scale,
+            white_level: self.white_level,
+        };
+
+        command_buffer_builder
+            .bind_pipeline_compute(resources.compute_pipeline.clone())
+            .unwrap()
+            .push_constants(resources.compute_pipeline.layout().clone(), 0, constants)
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                resources.compute_pipeline.layout().clone(),
+                0,
+                resources.descriptor_set.clone(),
+            )
+            .unwrap();
+
+        unsafe {
+            command_buffer_builder.dispatch(work_groups).unwrap();
+        }
+    }
+}
+
+impl StageInPipeline for Stage10 {
+    fn create_stage_resources(
+        &self,
+        context: &context::Context,
+        input: Option<StageOutput>,
+    ) -> StageResources {
+        let (_, recovered_image_view) = {
+            let image = Image::new(
+                context.memory_allocator.clone(),
+                ImageCreateInfo {
+                    format: Format::R16_SFLOAT,
+                    extent: self.extent,
+                    usage: ImageUsage::STORAGE,
+                    ..Default::default()
+                },
+                AllocationCreateInfo::default(),
+            )
+            .unwrap();
+
+            let view = ImageView::new_default(image.clone()).unwrap();
+
+            (image, view)
+        };
+
+        // The shader always has a mask to write to, a placeholder when it is not wanted
+        let mask_buffer = self.mask.clone().unwrap_or_else(|| {
+            Buffer::from_iter(
+                context.memory_allocator.clone(),
+                BufferCreateInfo {
+                    usage: BufferUsage::STORAGE_BUFFER,
+                    ..Default::default()
+                },
+                AllocationCreateInfo::default(),
+                [0u32],
+            )
+            .unwrap()
+        });
+
+        mod cs {
+            vulkano_shaders::shader! {
+                bytes: "shaders/finishing_10.spv"
+            }
+        }
+
+        let compute_pipeline =
+            stage::create_compute_pipeline(context, cs::load(context.device.clone()).unwrap());
+
+        let layout = compute_pipeline.layout().set_layouts().get(0).unwrap();
+        let descriptor_set = DescriptorSet::new(
+            context.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::image_view(
+                    0,
+                    input.unwrap().image_views.get(0).unwrap().clone(),
+                ),
+                WriteDescriptorSet::image_view(1, recovered_image_view.clone()),
+                WriteDescriptorSet::buffer(2, mask_buffer),
+                WriteDescriptorSet::image_view(3, self.lens_shading.clone()),
+            ],
+            [],
+        )
+        .unwrap();
+
+        StageResources {
+            compute_pipeline,
+            descriptor_set,
+            image_views: vec![recovered_image_view],
+            buffers: vec![],
+            commands: vec![],
+        }
+    }
+
+    fn bind_stage_pipeline_and_dispatch(
+        &self,
+        command_buffer_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        resources: &StageResources,
+        work_groups: [u32; 3],
+    ) {
+        #[derive(BufferContents)]
+        #[repr(C)]
+        struct Constants {
+            color_gains: [f32; 4],
+            cfa_pattern: [u32; 4],
+            cfa_size: [u32; 2],
+            cfa_origin: [u32; 2],
+            lens_shading_scale: [f32; 2],
+            mode: u32,
+            write_mask: u32,
+        }
+
+        let constants = Constants {
+            color_gains: self.color_gains,
+            cfa_pattern: self.cfa_pattern.packed(),
+            cfa_size: self.cfa_pattern.size(),
+            cfa_origin: self.cfa_origin,
+            lens_shading_scale: self.lens_shading_scale,
+            mode: match self.mode {
+                HighlightMode::Clip => 0,
+                HighlightMode::Blend => 1,
+                HighlightMode::Reconstruct => 2,
+            },
+            write_mask: self.mask.is_some() as u32,
+        };
+
+        command_buffer_builder
+            .bind_pipeline_compute(resources.compute_pipeline.clone())
+            .unwrap()
+            .push_constants(resources.compute_pipeline.layout().clone(), 0, constants)
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                resources.compute_pipeline.layout().clone(),
+                0,
+                resources.descriptor_set.clone(),
+            )
+            .unwrap();
+
+        unsafe {
+            command_buffer_builder.dispatch(work_groups).unwrap();
+        }
+    }
+}
+
+impl StageInPipeline for Stage11 {
+    fn create_stage_resources(
+        &self,
+        context: &context::Context,
+        input: Option<StageOutput>,
+    ) -> StageResources {
+        let (_, corrected_image_view) = {
+            let image = Image::new(
+                context.memory_allocator.clone(),
+                ImageCreateInfo {
+                    format: Format::R16_SFLOAT,
+                    extent: self.extent,
+                    usage: ImageUsage::STORAGE,
+                    ..Default::default()
+                },
+                AllocationCreateInfo::default(),
+            )
+            .unwrap();
+
+            let view = ImageView::new_default(image.clone()).unwrap();
+
+            (image, view)
+        };
+
+        // A single texel stands in for the map when there are no known defects
+        let defect_map_image_view = if self.defects.is_empty() {
+            create_defect_map_image_view(context, [1, 1, 1], &[])
+        } else {
+            create_defect_map_image_view(context, self.extent, &self.defects)
+        };
+
+        mod cs {
+            vulkano_shaders::shader! {
+                bytes: "shaders/finishing_11.spv"
+            }
+        }
+
+        let compute_pipeline =
+            stage::create_compute_pipeline(context, cs::load(context.device.clone()).unwrap());
+
+        let layout = compute_pipeline.layout().set_layouts().get(0).unwrap();
+        let descriptor_set = DescriptorSet::new(
+            context.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::image_view(
+                    0,
+                    input.unwrap().image_views.get(0).unwrap().clone(),
+                ),
+                WriteDescriptorSet::image_view(1, corrected_image_view.clone()),
+                WriteDescriptorSet::image_view(2, defect_map_image_view),
+            ],
+            [],
+        )
+        .unwrap();
+
+        StageResources {
+            compute_pipeline,
+            descriptor_set,
+            image_views: vec![corrected_image_view],
+            buffers: vec![],
+            commands: vec![],
+        }
+    }
+
+    fn bind_stage_pipeline_and_dispatch(
+        &self,
+        command_buffer_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        resources: &StageResources,
+        work_groups: [u32; 3],
+    ) {
+        #[derive(BufferContents)]
+        #[repr(C)]
+        struct Constants {
+            cfa_pattern: [u32; 4],
+            cfa_size: [u32; 2],
+            cfa_origin: [u32; 2],
+            threshold: f32,
+            detect: u32,
+            use_defect_map: u32,
+        }
+
+        let constants = Constants {
+            cfa_pattern: self.cfa_pattern.packed(),
+            cfa_size: self.cfa_pattern.size(),
+            cfa_origin: self.cfa_origin,
+            threshold: self.threshold.unwrap_or(0.0),
+            detect: self.threshold.is_some() as u32,
+            use_defect_map: !self.defects.is_empty() as u32,
+        };
+
+        command_buffer_builder
+            .bind_pipeline_compute(resources.compute_pipeline.clone())
+            .unwrap()
+            .push_constants(resources.compute_pipeline.layout().clone(), 0, constants)
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                resources.compute_pipeline.layout().clone(),
+                0,
+                resources.descriptor_set.clone(),
+            )
+            .unwrap();
+
+        unsafe {
+            command_buffer_builder.dispatch(work_groups).unwrap();
+        }
+    }
+}
+
+// Marks the defective rectangles in an image of the given extent
+fn create_defect_map_image_view(
+    context: &context::Context,
+    extent: [u32; 3],
+    defects: &[Rect],
+) -> Arc<ImageView> {
+    let mut map = vec![0u8; (extent[0] * extent[1]) as usize];
+    for defect in defects {
+        for y in defect.y..defect.y + defect.height {
+            for x in defect.x..defect.x + defect.width {
+                map[(y * extent[0] + x) as usize] = 1;
+            }
+        }
+    }
+
+    let staging_buffer = Buffer::from_iter(
+        context.memory_allocator.clone(),
+        BufferCreateInfo {
+            usage: BufferUsage::TRANSFER_SRC,
+            ..Default::default()
+        },
+        AllocationCreateInfo {
+            memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+            ..Default::default()
+        },
+        map,
+    )
+    .unwrap();
+
+    let image = Image::new(
+        context.memory_allocator.clone(),
+        ImageCreateInfo {
+            format: Format::R8_UINT,
+            extent,
+            usage: ImageUsage::STORAGE | ImageUsage::TRANSFER_DST,
+            ..Default::default()
+        },
+        AllocationCreateInfo::default(),
+    )
+    .unwrap();
+
+    let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
+        context.command_buffer_allocator.clone(),
+        context.queue.queue_family_index(),
+        CommandBufferUsage::OneTimeSubmit,
+    )
+    .unwrap();
+
+    command_buffer_builder
+        .copy_buffer_to_image(CopyBufferToImageInfo::buffer_image(
+            staging_buffer,
+            image.clone(),
+        ))
+        .unwrap();
+
+    let command_buffer = command_buffer_builder.build().unwrap();
+
+    command_buffer
+        .execute(context.queue.clone())
+        .unwrap()
+        .then_signal_fence_and_flush()
+        .unwrap()
+        .wait(None)
+        .unwrap();
+
+    ImageView::new_default(image).unwrap()
+}
+
+impl StageInPipeline for Stage12 {
+    fn create_stage_resources(
+        &self,
+        context: &context::Context,
+        input: Option<StageOutput>,
+    ) -> StageResources {
+        let (_, warped_image_view) = {
+            let image = Image::new(
+                context.memory_allocator.clone(),
+                ImageCreateInfo {
+                    format: Format::R16G16B16A16_SFLOAT,
+                    extent: self.extent,
+                    usage: ImageUsage::STORAGE,
+                    ..Default::default()
+                },
+                AllocationCreateInfo::default(),
+            )
+            .unwrap();
+
+            let view = ImageView::new_default(image.clone()).unwrap();
+
+            (image, view)
+        };
+
+        mod cs {
+            vulkano_shaders::shader! {
+                bytes: "shaders/finishing_12.spv"
+            }
+        }
+
+        let compute_pipeline =
+            stage::create_compute_pipeline(context, cs::load(context.device.clone()).unwrap());
+
+        let layout = compute_pipeline.layout().set_layouts().get(0).unwrap();
+        let descriptor_set = DescriptorSet::new(
+            context.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::image_view(
+                    0,
+                    input.unwrap().image_views.get(0).unwrap().clone(),
+                ),
+                WriteDescriptorSet::image_view(1, warped_image_view.clone()),
+            ],
+            [],
+        )
+        .unwrap();
+
+        StageResources {
+            compute_pipeline,
+            descriptor_set,
+            image_views: vec![warped_image_view],
+            buffers: vec![],
+            commands: vec![],
+        }
+    }
+
+    fn bind_stage_pipeline_and_dispatch(
+        &self,
+        command_buffer_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        resources: &StageResources,
+        work_groups: [u32; 3],
+    ) {
+        #[derive(BufferContents)]
+        #[repr(C)]
+        struct Constants {
+            center: [f32; 2],
+            focal_length: [f32; 2],
+            radial: [f32; 4],
+            tangential: [f32; 2],
+            skew: f32,
+            zoom: f32,
+            resampling: u32,
+        }
+
+        let constants = Constants {
+            center: self.distortion.center,
+            focal_length: self.distortion.focal_length,
+            radial: self.distortion.radial,
+            tangential: self.distortion.tangential,
+            skew: self.distortion.skew,
+            zoom: self.zoom,
+            resampling: match self.resampling {
+                Resampling::Bicubic => 0,
+                Resampling::Lanczos => 1,
+            },
+        };
+
+        command_buffer_builder
+            .bind_pipeline_compute(resources.compute_pipeline.clone())
+            .unwrap()
+            .push_constants(resources.compute_pipeline.layout().clone(), 0, constants)
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                resources.compute_pipeline.layout().clone(),
+                0,
+                resources.descriptor_set.clone(),
+            )
+            .unwrap();
+
+        unsafe {
+            command_buffer_builder.dispatch(work_groups).unwrap();
+        }
+    }
+}
+
+impl StageInPipeline for Stage13 {
+    fn create_stage_resources(
+        &self,
+        context: &context::Context,
+        input: Option<StageOutput>,
+    ) -> StageResources {
+        let work_groups = stage::work_group_count(self.extent, context.work_group_size);
+
+        // Products of the least squares fit, per workgroup
+        let sums_buffer = Buffer::from_iter(
+            context.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                    | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                ..Default::default()
+            },
+            (0..work_groups[0] * work_groups[1] * 12).map(|_| 0f32),
+        )
+        .unwrap();
+
+        mod cs {
+            vulkano_shaders::shader! {
+                bytes: "shaders/finishing_13.spv"
+            }
+        }
+
+        let compute_pipeline =
+            stage::create_compute_pipeline(context, cs::load(context.device.clone()).unwrap());
+
+        let layout = compute_pipeline.layout().set_layouts().get(0).unwrap();
+        let descriptor_set = DescriptorSet::new(
+            context.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::image_view(
+                    0,
+                    input.as_ref().unwrap().image_views.get(0).unwrap().clone(),
+                ),
+                WriteDescriptorSet::buffer(1, sums_buffer.clone()),
+            ],
+            [],
+        )
+        .unwrap();
+
+        // The demosaiced image passes through, the statistics are read back by the host
+        StageResources {
+            compute_pipeline,
+            descriptor_set,
+            image_views: input.unwrap().image_views,
+            buffers: vec![sums_buffer.into_bytes()],
+            commands: vec![],
+        }
+    }
+
+    fn bind_stage_pipeline_and_dispatch(
+        &self,
+        command_buffer_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        resources: &StageResources,
+        work_groups: [u32; 3],
+    ) {
+        #[derive(BufferContents)]
+        #[repr(C)]
+        struct Constants {
+            center: [f32; 2],
+            radius: f32,
+        }
+
+        let constants = Constants {
+            center: self.center,
+            radius: self.radius,
+        };
+
+        command_buffer_builder
+            .bind_pipeline_compute(resources.compute_pipeline.clone())
+            .unwrap()
+            .push_constants(resources.compute_pipeline.layout().clone(), 0, constants)
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                resources.compute_pipeline.layout().clone(),
+                0,
+                resources.descriptor_set.clone(),
+            )
+            .unwrap();
+
+        unsafe {
+            command_buffer_builder.dispatch(work_groups).unwrap();
+        }
+    }
+}
+
+impl StageInPipeline for Stage14 {
+    fn create_stage_resources(
+        &self,
+        context: &context::Context,
+        input: Option<StageOutput>,
+    ) -> StageResources {
+        let (_, corrected_image_view) = {
+            let image = Image::new(
+                context.memory_allocator.clone(),
+                ImageCreateInfo {
+                    format: Format::R16G16B16A16_SFLOAT,
+                    extent: self.extent,
+                    usage: ImageUsage::STORAGE,
+                    ..Default::default()
+                },
+                AllocationCreateInfo::default(),
+            )
+            .unwrap();
+
+            let view = ImageView::new_default(image.clone()).unwrap();
+
+            (image, view)
+        };
+
+        mod cs {
+            vulkano_shaders::shader! {
+                bytes: "shaders/finishing_14.spv"
+            }
+        }
+
+        let compute_pipeline =
+            stage::create_compute_pipeline(context, cs::load(context.device.clone()).unwrap());
+
+        let layout = compute_pipeline.layout().set_layouts().get(0).unwrap();
+        let descriptor_set = DescriptorSet::new(
+            context.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::image_view(
+                    0,
+                    input.unwrap().image_views.get(0).unwrap().clone(),
+                ),
+                WriteDescriptorSet::image_view(1, corrected_image_view.clone()),
+            ],
+            [],
+        )
+        .unwrap();
+
+        StageResources {
+            compute_pipeline,
+            descriptor_set,
+            image_views: vec![corrected_image_view],
+            buffers: vec![],
+            commands: vec![],
+        }
+    }
+
+    fn bind_stage_pipeline_and_dispatch(
+        &self,
+        command_buffer_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        resources: &StageResources,
+        work_groups: [u32; 3],
+    ) {
+        #[derive(BufferContents)]
+        #[repr(C)]
+        struct Constants {
+            red: [f32; 4],
+            blue: [f32; 4],
+            center: [f32; 2],
+            radius: f32,
+        }
+
+        let constants = Constants {
+            red: self.aberration.red,
+            blue: self.aberration.blue,
+            center: self.aberration.center,
+            radius: self.aberration.radius,
+        };
+
+        command_buffer_builder
+            .bind_pipeline_compute(resources.compute_pipeline.clone())
+            .unwrap()
+            .push_constants(resources.compute_pipeline.layout().clone(), 0, constants)
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                resources.compute_pipeline.layout().clone(),
+                0,
+                resources.descriptor_set.clone(),
+            )
+            .unwrap();
+
+        unsafe {
+            command_buffer_builder.dispatch(work_groups).unwrap();
+        }
+    }
+}
+
+impl StageInPipeline for Stage15 {
+    fn create_stage_resources(
+        &self,
+        context: &context::Context,
+        input: Option<StageOutput>,
+    ) -> StageResources {
+        mod cs {
+            vulkano_shaders::shader! {
+                bytes: "shaders/finishing_15.spv"
+            }
+        }
+
+        let compute_pipeline =
+            stage::create_compute_pipeline(context, cs::load(context.device.clone()).unwrap());
+
+        let layout = compute_pipeline.layout().set_layouts().get(0).unwrap();
+        let descriptor_set = DescriptorSet::new(
+            context.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [WriteDescriptorSet::image_view(
+                0,
+                input.as_ref().unwrap().image_views.get(0).unwrap().clone(),
+            )],
+            [],
+        )
+        .unwrap();
+
+        StageResources {
+            compute_pipeline,
+            descriptor_set,
+            image_views: input.unwrap().image_views,
+            buffers: vec![],
+            commands: vec![],
+        }
+    }
+
+    fn bind_stage_pipeline_and_dispatch(
+        &self,
+        command_buffer_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        resources: &StageResources,
+        work_groups: [u32; 3],
+    ) {
+        #[derive(BufferContents)]
+        #[repr(C)]
+        struct Constants {
+            aces_input: [[f32; 4]; 3],
+            aces_output: [[f32; 4]; 3],
+            parameters: [f32; 4],
+            tone_operator: u32,
+        }
+
+        let rows = |m: &[f32; 9]| {
+            [
+                [m[0], m[1], m[2], 0.0 /* padding */],
+                [m[3], m[4], m[5], 0.0 /* padding */],
+                [m[6], m[7], m[8], 0.0 /* padding */],
+            ]
+        };
+
+        let (tone_operator, parameters) = self.tone_mapping.push_constants();
+        let constants = Constants {
+            aces_input: rows(&self.aces_input),
+            aces_output: rows(&self.aces_output),
+            parameters,
+            tone_operator,
+        };
+
+        command_buffer_builder
+            .bind_pipeline_compute(resources.compute_pipeline.clone())
+            .unwrap()
+            .push_constants(resources.compute_pipeline.layout().clone(), 0, constants)
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                resources.compute_pipeline.layout().clone(),
+                0,
+                resources.descriptor_set.clone(),
+            )
+            .unwrap();
+
+        unsafe {
+            command_buffer_builder.dispatch(work_groups).unwrap();
+        }
+    }
+}
+
+impl StageInPipeline for Stage16 {
+    fn create_stage_resources(
+        &self,
+        context: &context::Context,
+        input: Option<StageOutput>,
+    ) -> StageResources {
+        let (_, output_image_view) = {
+            let image = Image::new(
+                context.memory_allocator.clone(),
+                ImageCreateInfo {
+                    format: Format::R16G16B16A16_SFLOAT,
+                    extent: self.extent,
+                    usage: ImageUsage::STORAGE,
+                    ..Default::default()
+                },
+                AllocationCreateInfo::default(),
+            )
+            .unwrap();
+
+            let view = ImageView::new_default(image.clone()).unwrap();
+
+            (image, view)
+        };
+
+        mod cs {
+            vulkano_shaders::shader! {
+                bytes: "shaders/finishing_16.spv"
+            }
+        }
+
+        let compute_pipeline =
+            stage::create_compute_pipeline(context, cs::load(context.device.clone()).unwrap());
+
+        let layout = compute_pipeline.layout().set_layouts().get(0).unwrap();
+        let descriptor_set = DescriptorSet::new(
+            context.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::image_view(
+                    0,
+                    input.unwrap().image_views.get(0).unwrap().clone(),
+                ),
+                WriteDescriptorSet::image_view(1, output_image_view.clone()),
+                WriteDescriptorSet::image_view(2, self.exposures.levels[0].clone()),
+                WriteDescriptorSet::image_view(3, self.fused.levels[0].clone()),
+            ],
+            [],
+        )
+        .unwrap();
+
+        StageResources {
+            compute_pipeline,
+            descriptor_set,
+            image_views: vec![output_image_view],
+            buffers: vec![],
+            commands: vec![],
+        }
+    }
+
+    fn bind_stage_pipeline_and_dispatch(
+        &self,
+        command_buffer_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        resources: &StageResources,
+        work_groups: [u32; 3],
+    ) {
+        #[derive(BufferContents)]
+        #[repr(C)]
+        struct Constants {
+            luminance: [f32; 4],
+            compression: f32,
+            pass: u32,
+        }
+
+        // Exposures first, then the pyramids, then the fused luminance back onto the colours
+        for pass in 0..2 {
+            if pass == 1 {
+                for pyramid_pass in &self.passes {
+                    pyramid_pass.record(command_buffer_builder);
+                }
+            }
+
+            let constants = Constants {
+                luminance: [
+                    self.luminance[0],
+                    self.luminance[1],
+                    self.luminance[2],
+                    0.0, /* padding */
+                ],
+                compression: self.compression,
+                pass,
+            };
+
+            command_buffer_builder
+                .bind_pipeline_compute(resources.compute_pipeline.clone())
+                .unwrap()
+                .push_constants(resources.compute_pipeline.layout().clone(), 0, constants)
+                .unwrap()
+                .bind_descriptor_sets(
+                    PipelineBindPoint::Compute,
+                    resources.compute_pipeline.layout().clone(),
+                    0,
+                    resources.descriptor_set.clone(),
+                )
+                .unwrap();
+
+            unsafe {
+                command_buffer_builder.dispatch(work_groups).unwrap();
+            }
+        }
+    }
+}
+
+impl StageInPipeline for Stage20 {
+    fn create_stage_resources(
+        &self,
+        context: &context::Context,
+        input: Option<StageOutput>,
+    ) -> StageResources {
+        let histogram_buffer = Buffer::from_iter(
+            context.memory_allocator.clone(),
+            BufferCreateInfo {
+                usage: BufferUsage::STORAGE_BUFFER,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                    | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                ..Default::default()
+            },
+            (0..tone::EXPOSURE_HISTOGRAM_BINS as u32).map(|_| 0u32),
+        )
+        .unwrap();
+
+        mod cs {
+            vulkano_shaders::shader! {
+                bytes: "shaders/finishing_20.spv"
+            }
+        }
+
+        let compute_pipeline =
+            stage::create_compute_pipeline(context, cs::load(context.device.clone()).unwrap());
+
+        let layout = compute_pipeline.layout().set_layouts().get(0).unwrap();
+        let descriptor_set = DescriptorSet::new(
+            context.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::image_view(
+                    0,
+                    input.as_ref().unwrap().image_views.get(0).unwrap().clone(),
+                ),
+                WriteDescriptorSet::buffer(1, histogram_buffer.clone()),
+            ],
+            [],
+        )
+        .unwrap();
+
+        // The normalized raw passes through, the histogram is read back by the host
+        StageResources {
+            compute_pipeline,
+            descriptor_set,
+            image_views: input.unwrap().image_views,
+            buffers: vec![histogram_buffer.into_bytes()],
+            commands: vec![],
+        }
+    }
+
+    fn bind_stage_pipeline_and_dispatch(
+        &self,
+        command_buffer_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        resources: &StageResources,
+        work_groups: [u32; 3],
+    ) {
+        #[derive(BufferContents)]
+        #[repr(C)]
+        struct Constants {
+            luminance: [f32; 4],
+            cfa_pattern: [u32; 4],
+            cfa_size: [u32; 2],
+            cfa_origin: [u32; 2],
+        }
+
+        let constants = Constants {
+            luminance: [
+                self.luminance[0],
+                self.luminance[1],
+                self.luminance[2],
+                0.0, /* padding */
+            ],
+            cfa_pattern: self.cfa_pattern.packed(),
+            cfa_size: self.cfa_pattern.size(),
+            cfa_origin: self.cfa_origin,
+        };
+
+        command_buffer_builder
+            .bind_pipeline_compute(resources.compute_pipeline.clone())
+            .unwrap()
+            .push_constants(resources.compute_pipeline.layout().clone(), 0, constants)
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                resources.compute_pipeline.layout().clone(),
+                0,
+                resources.descriptor_set.clone(),
+            )
+            .unwrap();
+
+        unsafe {
+            command_buffer_builder.dispatch(work_groups).unwrap();
+        }
+    }
+}
+
+impl StageInPipeline for Stage21 {
+    fn create_stage_resources(
+        &self,
+        context: &context::Context,
+        input: Option<StageOutput>,
+    ) -> StageResources {
+        let table_image_view = create_lut_image_view(context, &self.look.lut);
+
+        mod cs {
+            vulkano_shaders::shader! {
+                bytes: "shaders/finishing_21.spv"
+            }
+        }
+
+        let compute_pipeline =
+            stage::create_compute_pipeline(context, cs::load(context.device.clone()).unwrap());
+
+        let layout = compute_pipeline.layout().set_layouts().get(0).unwrap();
+        let descriptor_set = DescriptorSet::new(
+            context.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [
+                WriteDescriptorSet::image_view(
+                    0,
+                    input.as_ref().unwrap().image_views.get(0).unwrap().clone(),
+                ),
+                WriteDescriptorSet::image_view(1, table_image_view),
+            ],
+            [],
+        )
+        .unwrap();
+
+        StageResources {
+            compute_pipeline,
+            descriptor_set,
+            image_views: input.unwrap().image_views,
+            buffers: vec![],
+            commands: vec![],
+        }
+    }
+
+    fn bind_stage_pipeline_and_dispatch(
+        &self,
+        command_buffer_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        resources: &StageResources,
+        work_groups: [u32; 3],
+    ) {
+        #[derive(BufferContents)]
+        #[repr(C)]
+        struct Constants {
+            domain_min: [f32; 4],
+            domain_max: [f32; 4],
+            size: u32,
+            one_dimensional: u32,
+            tetrahedral: u32,
+        }
+
+        let lut = &self.look.lut;
+        let constants = Constants {
+            domain_min: [
+                lut.domain_min[0],
+                lut.domain_min[1],
+                lut.domain_min[2],
+                0.0, /* padding */
+            ],
+            domain_max: [
+                lut.domain_max[0],
+                lut.domain_max[1],
+                lut.domain_max[2],
+                0.0, /* padding */
+            ],
+            size: lut.size,
+            one_dimensional: (lut.kind == LutKind::OneDimensional) as u32,
+            tetrahedral: (self.look.interpolation == LutInterpolation::Tetrahedral) as u32,
+        };
+
+        command_buffer_builder
+            .bind_pipeline_compute(resources.compute_pipeline.clone())
+            .unwrap()
+            .push_constants(resources.compute_pipeline.layout().clone(), 0, constants)
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                resources.compute_pipeline.layout().clone(),
+                0,
+                resources.descriptor_set.clone(),
+            )
+            .unwrap();
+
+        unsafe {
+            command_buffer_builder.dispatch(work_groups).unwrap();
+        }
+    }
+}
+
+// A three dimensional image holding the table, a row of it for one dimensional tables
+fn create_lut_image_view(context: &context::Context, lut: &Lut) -> Arc<ImageView> {
+    let staging_buffer = Buffer::from_iter(
+        context.memory_allocator.clone(),
+        BufferCreateInfo {
+            usage: BufferUsage::TRANSFER_SRC,
+            ..Default::default()
+        },
+        AllocationCreateInfo {
+            memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+            ..Default::default()
+        },
+        lut.table.iter().map(|&[r, g, b]| [r, g, b, 1.0]),
+    )
+    .unwrap();
+
+    let image = Image::new(
+        context.memory_allocator.clone(),
+        ImageCreateInfo {
+            image_type: ImageType::Dim3d,
+            format: Format::R32G32B32A32_SFLOAT,
+            extent: lut.extent(),
+            usage: ImageUsage::STORAGE | ImageUsage::TRANSFER_DST,
+            ..Default::default()
+        },
+        AllocationCreateInfo::default(),
+    )
+    .unwrap();
+
+    let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
+        context.command_buffer_allocator.clone(),
+        context.queue.queue_family_index(),
+        CommandBufferUsage::OneTimeSubmit,
+    )
+    .unwrap();
+
+    command_buffer_builder
+        .copy_buffer_to_image(CopyBufferToImageInfo::buffer_image(
+            staging_buffer,
+            image.clone(),
+        ))
+        .unwrap();
+
+    let command_buffer = command_buffer_builder.build().unwrap();
+
+    command_buffer
+        .execute(context.queue.clone())
+        .unwrap()
+        .then_signal_fence_and_flush()
+        .unwrap()
+        .wait(None)
+        .unwrap();
+
+    ImageView::new_default(image).unwrap()
+}
+
+impl StageInPipeline for Stage22 {
+    fn create_stage_resources(
+        &self,
+        context: &context::Context,
+        input: Option<StageOutput>,
+    ) -> StageResources {
+        mod cs {
+            vulkano_shaders::shader! {
+                bytes: "shaders/finishing_22.spv"
+            }
+        }
+
+        let compute_pipeline =
+            stage::create_compute_pipeline(context, cs::load(context.device.clone()).unwrap());
+
+        let layout = compute_pipeline.layout().set_layouts().get(0).unwrap();
+        let descriptor_set = DescriptorSet::new(
+            context.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [WriteDescriptorSet::image_view(
+                0,
+                input.as_ref().unwrap().image_views.get(0).unwrap().clone(),
+            )],
+            [],
+        )
+        .unwrap();
+
+        StageResources {
+            compute_pipeline,
+            descriptor_set,
+            image_views: input.unwrap().image_views,
+            buffers: vec![],
+            commands: vec![],
+        }
+    }
+
+    fn bind_stage_pipeline_and_dispatch(
+        &self,
+        command_buffer_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        resources: &StageResources,
+        work_groups: [u32; 3],
+    ) {
+        #[derive(BufferContents)]
+        #[repr(C)]
+        struct Constants {
+            rgb_to_lms: [[f32; 4]; 3],
+            lms_to_rgb: [[f32; 4]; 3],
+            saturation: f32,
+            vibrance: f32,
+            contrast: f32,
+            pivot: f32,
+            black: f32,
+            white: f32,
+        }
+
+        let rows = |m: &[f32; 9]| {
+            [
+                [m[0], m[1], m[2], 0.0 /* padding */],
+                [m[3], m[4], m[5], 0.0 /* padding */],
+                [m[6], m[7], m[8], 0.0 /* padding */],
+            ]
+        };
+
+        let constants = Constants {
+            rgb_to_lms: rows(&self.rgb_to_lms),
+            lms_to_rgb: rows(&self.lms_to_rgb),
+            saturation: self.adjustment.saturation,
+            vibrance: self.adjustment.vibrance,
+            contrast: self.adjustment.contrast,
+            // As OKLab lightness, which is the cube root of the luminance for neutrals
+            pivot: self.adjustment.pivot.cbrt(),
+            black: self.adjustment.black,
+            white: self.adjustment.white,
+        };
+
+        command_buffer_builder
+            .bind_pipeline_compute(resources.compute_pipeline.clone())
+            .unwrap()
+            .push_constants(resources.compute_pipeline.layout().clone(), 0, constants)
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                resources.compute_pipeline.layout().clone(),
+                0,
+                resources.descriptor_set.clone(),
+            )
+            .unwrap();
+
+        unsafe {
+            command_buffer_builder.dispatch(work_groups).unwrap();
+        }
+    }
+}
+
+impl StageInPipeline for Stage23 {
+    fn create_stage_resources(
+        &self,
+        context: &context::Context,
+        input: Option<StageOutput>,
+    ) -> StageResources {
+        mod cs {
+            vulkano_shaders::shader! {
+                bytes: "shaders/finishing_23.spv"
+            }
+        }
+
+        let compute_pipeline =
+            stage::create_compute_pipeline(context, cs::load(context.device.clone()).unwrap());
+
+        let layout = compute_pipeline.layout().set_layouts().get(0).unwrap();
+        let descriptor_set = DescriptorSet::new(
+            context.descriptor_set_allocator.clone(),
+            layout.clone(),
+            [WriteDescriptorSet::image_view(
+                0,
+                input.as_ref().unwrap().image_views.get(0).unwrap().clone(),
+            )],
+            [],
+        )
+        .unwrap();
+
+        StageResources {
+            compute_pipeline,
+            descriptor_set,
+            image_views: input.unwrap().image_views,
+            buffers: vec![],
+            commands: vec![],
+        }
+    }
+
+    fn bind_stage_pipeline_and_dispatch(
+        &self,
+        command_buffer_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        resources: &StageResources,
+        work_groups: [u32; 3],
+    ) {
+        #[derive(BufferContents)]
+        #[repr(C)]
+        struct Constants {
+            threshold: [f32; 4],
+            limit: [f32; 4],
+            power: f32,
+        }
+
+        let GamutCompression {
+            threshold,
+            limit,
+            power,
+        } = self.gamut_compression;
+        let constants = Constants {
+            threshold: [
+                threshold[0],
+                threshold[1],
+                threshold[2],
+                0.0, /* padding */
+            ],
+            limit: [limit[0], limit[1], limit[2], 0.0 /* padding */],
+            power,
+        };
+
+        command_buffer_builder
+            .bind_pipeline_compute(resources.compute_pipeline.clone())
+            .unwrap()
+            .push_constants(resources.compute_pipeline.layout().clone(), 0, constants)
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                resources.compute_pipeline.layout().clone(),
+                0,
+                resources.descriptor_set.clone(),
+            )
+            .unwrap();
+
+        unsafe {
+            command_buffer_builder.dispatch(work_groups).unwrap();
+        }
+    }
+}
+
+// Exposure fusion of every level of the exposures pyramid into the fused one, each against the
+// next coarser level, see shaders/finishing_19.slang
+fn create_exposure_fusion_passes(
+    context: &context::Context,
+    exposures: &Pyramid,
+    fused: &Pyramid,
+) -> Vec<Pass> {
+    mod cs {
+        vulkano_shaders::shader! {
+            bytes: "shaders/finishing_19.spv"
+        }
+    }
+
+    let compute_pipeline =
+        stage::create_compute_pipeline(context, cs::load(context.device.clone()).unwrap());
+
+    let layout = compute_pipeline.layout().set_layouts().get(0).unwrap();
+
+    (0..exposures.levels.len())
+        .map(|level| {
+            // The coarsest level stands in for its own coarser level
+            let coarser = exposures
+                .levels
+                .get(level + 1)
+                .unwrap_or(&exposures.levels[level]);
+
+            let descriptor_set = DescriptorSet::new(
+                context.descriptor_set_allocator.clone(),
+                layout.clone(),
+                [
+                    WriteDescriptorSet::image_view(0, exposures.levels[level].clone()),
+                    WriteDescriptorSet::image_view(1, coarser.clone()),
+                    WriteDescriptorSet::image_view(2, fused.levels[level].clone()),
+                ],
+                [],
+            )
+            .unwrap();
+
+            Pass {
+                compute_pipeline: compute_pipeline.clone(),
+                descriptor_set,
+                work_groups: stage::work_group_count(
+                    exposures.levels[level].image().extent(),
+                    context.work_group_size,
+                ),
+            }
+        })
+        .collect()
+}
+
+// Mean of each position of the black level pattern over the optical black regions, nothing
+// when there are none or they fall outside the raw buffer. The buffer holds 16-bit samples in
+// native byte order, with no alignment guaranteed.
+fn estimate_black_level(
+    raw: &[u8],
+    width: u32,
+    active_area: Rect,
+    regions: &[Rect],
+) -> Option<[f32; 4]> {
+    let sample = |x: u32, y: u32| {
+        let offset = 2 * (y as usize * width as usize + x as usize);
+        let bytes = raw.get(offset..offset + 2)?;
+        Some(u16::from_ne_bytes([bytes[0], bytes[1]]))
+    };
+
+    let mut sum = [0u64; 4];
+    let mut count = [0u64; 4];
+
+    for region in regions {
+        for y in region.y..region.y + region.height {
+            for x in region.x..region.x + region.width {
+                // The pattern starts at the active area
+                let index = ((y.wrapping_sub(active_area.y) & 1) * 2
+                    + (x.wrapping_sub(active_area.x) & 1)) as usize;
+
+                sum[index] += sample(x, y)? as u64;
+                count[index] += 1;
+            }
+        }
+    }
+
+    if count.contains(&0) {
+        return None;
+    }
+
+    Some([0, 1, 2, 3].map(|i| sum[i] as f32 / count[i] as f32))
+}
+
+// Uploads a gain map as an image with one texel per grid point
+fn create_gain_map_image_view(context: &context::Context, gain_map: &GainMap) -> Arc<ImageView> {
+    let staging_buffer = Buffer::from_iter(
+        context.memory_allocator.clone(),
+        BufferCreateInfo {
+            usage: BufferUsage::TRANSFER_SRC,
+            ..Default::default()
+        },
+        AllocationCreateInfo {
+            memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+            ..Default::default()
+        },
+        gain_map.gains.iter().copied(),
+    )
+    .unwrap();
+
+    let image = Image::new(
+        context.memory_allocator.clone(),
+        ImageCreateInfo {
+            format: Format::R32G32B32A32_SFLOAT,
+            extent: [gain_map.size[0], gain_map.size[1], 1],
+            usage: ImageUsage::STORAGE | ImageUsage::TRANSFER_DST,
+            ..Default::default()
+        },
+        AllocationCreateInfo::default(),
+    )
+    .unwrap();
+
+    let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
+        context.command_buffer_allocator.clone(),
+        context.queue.queue_family_index(),
+        CommandBufferUsage::OneTimeSubmit,
+    )
+    .unwrap();
+
+    command_buffer_builder
+        .copy_buffer_to_image(CopyBufferToImageInfo::buffer_image(
+            staging_buffer,
+            image.clone(),
+        ))
+        .unwrap();
+
+    let command_buffer = command_buffer_builder.build().unwrap();
+
+    command_buffer
+        .execute(context.queue.clone())
+        .unwrap()
+        .then_signal_fence_and_flush()
+        .unwrap()
+        .wait(None)
+        .unwrap();
+
+    ImageView::new_default(image).unwrap()
+}
+
+// Uploads the whole raw buffer, it becomes the input of the first stage
+fn create_raw_image_view(
+    context: &context::Context,
+    raw: &[u8],
+    extent: [u32; 3],
+) -> Arc<ImageView> {
+    let staging_buffer = Buffer::new_slice::<u8>(
+        context.memory_allocator.clone(),
+        BufferCreateInfo {
+            usage: BufferUsage::TRANSFER_SRC,
+            ..Default::default()
+        },
+        AllocationCreateInfo {
+            memory_type_filter: MemoryTypeFilter::PREFER_HOST
+                | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+            ..Default::default()
+        },
+        raw.len() as DeviceSize,
+    )
+    .unwrap();
+
+    // Lock subbufer and copy the entire RAW data into it
+    staging_buffer
+        .write()
+        .expect("Failed to lock subbufer for writing")
+        .copy_from_slice(raw);
+
+    let image = Image::new(
+        context.memory_allocator.clone(),
+        ImageCreateInfo {
+            format: Format::R16_UINT,
+            extent,
+            usage: ImageUsage::STORAGE | ImageUsage::TRANSFER_DST,
+            ..Default::default()
+        },
+        AllocationCreateInfo::default(),
+    )
+    .unwrap();
+
+    let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
+        context.command_buffer_allocator.clone(),
+        context.queue.queue_family_index(),
+        CommandBufferUsage::OneTimeSubmit,
+    )
+    .unwrap();
+
+    command_buffer_builder
+        .copy_buffer_to_image(CopyBufferToImageInfo::buffer_image(
+            staging_buffer,
+            image.clone(),
+        ))
+        .unwrap();
+
+    let command_buffer = command_buffer_builder.build().unwrap();
+
+    command_buffer
+        .execute(context.queue.clone())
+        .unwrap()
+        .then_signal_fence_and_flush()
+        .unwrap()
+        .wait(None)
+        .unwrap();
+
+    ImageView::new_default(image).unwrap()
+}
+
+// Channels, bytes per channel and whether they are half floats, for the output formats
+fn output_layout(format: Format) -> (usize, usize, bool) {
+    match format {
+        Format::R8_UNORM => (1, 1, false),
+        Format::R8G8B8A8_UNORM => (4, 1, false),
+        Format::R16_UNORM => (1, 2, false),
+        Format::R16G16B16A16_UNORM => (4, 2, false),
+        Format::R16G16B16A16_SFLOAT => (4, 2, true),
+        _ => panic!("Unexpected output format {format:?}"),
+    }
+}
+
+fn half_to_unorm16(bits: u16) -> u16 {
+    let exponent = ((bits >> 10) & 0x1f) as i32;
+    let mantissa = (bits & 0x3ff) as f32;
+
+    let value = match exponent {
+        0 => mantissa * (-24f32).exp2(),
+        31 if mantissa == 0.0 => f32::INFINITY,
+        31 => 0.0,
+        _ => (1.0 + mantissa / 1024.0) * ((exponent - 15) as f32).exp2(),
+    };
+    let value = if bits & 0x8000 != 0 { -value } else { value };
+
+    (value.clamp(0.0, 1.0) * 65535.0).round() as u16
+}
+
+// Repacks quantized pixels stored in one output format into another of the same bit depth,
+// dropping channels and turning half floats into integers
+fn convert_output(data: &[u8], stored: Format, requested: Format) -> Vec<u8> {
+    if stored == requested {
+        return data.to_vec();
+    }
+
+    let (stored_channels, bytes, float) = output_layout(stored);
+    let (requested_channels, requested_bytes, _) = output_layout(requested);
+    assert_eq!(bytes, requested_bytes, "Output bit depth changed");
+
+    data.chunks_exact(stored_channels * bytes)
+        .flat_map(|pixel| {
+            pixel
+                .chunks_exact(bytes)
+                .take(requested_channels)
+                .flat_map(|sample| match (bytes, float) {
+                    (2, true) => half_to_unorm16(u16::from_ne_bytes([sample[0], sample[1]]))
+                        .to_ne_bytes()
+                        .to_vec(),
+                    _ => sample.to_vec(),
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+// Records and runs the stages in a single submission, returning the output of the last one
+fn run_stages(
+    context: &context::Context,
+    stages: &[&dyn StageInPipeline],
+    input: StageOutput,
+    work_groups: [u32; 3],
+) -> StageOutput {
+    let mut command_buffer_builder = AutoCommandBufferBuilder::primary(
+        context.command_buffer_allocator.clone(),
+        context.queue.queue_family_index(),
+        CommandBufferUsage::OneTimeSubmit,
+    )
+    .unwrap();
+
+    let mut stage_output = input;
+
+    for stage in stages {
+        let resources = stage.create_stage_resources(context, Some(stage_output));
+        stage.bind_stage_pipeline_and_dispatch(
+            &mut command_buffer_builder,
+            &resources,
+            work_groups,
+        );
+        stage_output = StageOutput {
+            image_views: resources.image_views,
+            buffers: resources.buffers,
+            commands: resources.commands,
+        }
+    }
+
+    let command_buffer = command_buffer_builder.build().unwrap();
+
+    sync::now(context.device.clone())
+        .then_execute(context.queue.clone(), command_buffer)
+        .unwrap()
+        .then_signal_fence_and_flush()
+        .unwrap()
+        .wait(None)
+        .unwrap();
+
+    stage_output
+}
+
+pub struct Finish {
+    output: Option<Subbuffer<[u8]>>,
+    // Format the output was stored in, and the one it was asked for
+    output_formats: Option<(Format, Format)>,
+    estimated_gains: Option<[f32; 4]>,
+    estimated_exposure: Option<f32>,
+    highlight_mask: Option<Subbuffer<[u32]>>,
+}
+
+impl Finish {
+    pub fn new() -> Finish {
+        Finish {
+            output: None,
+            output_formats: None,
+            estimated_gains: None,
+            estimated_exposure: None,
+            highlight_mask: None,
+        }
+    }
+
+    pub fn finish(&mut self, context: &context::Context, raw: &[u8], parameters: &Parameters) {
+        let size = parameters.size;
+        let active_area = parameters.active_area;
+        let crop = parameters.crop;
+        let cfa_pattern = parameters.cfa_pattern;
+
+        assert!(
+            Rect::new(0, 0, size[0], size[1]).contains(&active_area),
+            "Active area {active_area:?} exceeds the raw buffer",
+        );
+        assert!(
+            Rect::new(0, 0, active_area.width, active_area.height).contains(&crop),
+            "Crop {crop:?} exceeds the active area",
+        );
+        for region in &parameters.optical_black_regions {
+            assert!(
+                Rect::new(0, 0, size[0], size[1]).contains(region),
+                "Optical black region {region:?} exceeds the raw buffer",
+            );
+        }
+
+        assert!(
+            raw.len() >= size[0] as usize * size[1] as usize * 2,
+            "Raw buffer of {} bytes is smaller than {size:?}",
+            raw.len(),
+        );
+
+        let raw_extent = [size[0], size[1], 1];
+        let extent = [crop.width, crop.height, 1];
+
+        let work_groups = stage::work_group_count(extent, context.work_group_size);
+
+        // Black and white levels of this frame: measured on the optical black regions when asked
+        // to, otherwise the per-frame levels reported with the capture and the static ones last
+        let black_level = parameters
+            .estimate_black_level
+            .then(|| {
+                estimate_black_level(raw, size[0], active_area, &parameters.optical_black_regions)
+            })
+            .flatten()
+            .or(parameters.dynamic_black_level)
+            .unwrap_or(parameters.black_level);
+        let white_level = parameters
+            .dynamic_white_level
+            .unwrap_or(parameters.white_level);
+
+        let raw_image_view = create_raw_image_view(context, raw, raw_extent);
+
+        // Lens shading gains, corrected along with the black level and needed wherever the
+        // white level matters. Active area coordinates map to the grid corners to corners.
+        let lens_shading_map = parameters
+            .lens_shading_map
+            .clone()
+            .unwrap_or_else(GainMap::identity);
+        let lens_shading = create_gain_map_image_view(context, &lens_shading_map);
+        let lens_shading_scale = [0, 1].map(|i| {
+            (lens_shading_map.size[i] - 1) as f32 / (active_area.size()[i].max(2) - 1) as f32
+        });
+
+        // White balance statistics, in a submission of their own since the gains they give are
+        // needed to set up the remaining stages
+        self.estimated_gains = match parameters.white_balance.awb_method() {
+            Some(method) if !cfa_pattern.is_monochrome() => {
+                let stage9 = Stage9 {
+                    raw_offset: [active_area.x + crop.x, active_area.y + crop.y],
+                    black_level,
+                    white_level,
+                    lens_shading: lens_shading.clone(),
+                    lens_shading_scale,
+                    cfa_pattern,
+                    cfa_origin: crop.origin(),
+                    extent,
+                };
+
+                let stage_output = run_stages(
+                    context,
+                    &[&stage9],
+                    StageOutput {
+                        image_views: vec![raw_image_view.clone()],
+                        ..Default::default()
+                    },
+                    work_groups,
+                );
+
+                let sums = stage_output.buffers[0].clone().reinterpret::<[f32]>();
+                let histogram = stage_output.buffers[1].clone().reinterpret::<[u32]>();
+
+                AwbStatistics::new(&sums.read().unwrap(), &histogram.read().unwrap()).gains(method)
+            }
+            _ => None,
+        };
+
+        // Crop to the final image, black level subtraction, lens shading correction, white
+        // balancing and normalization. The CFA, the black level pattern and the gain map start
+        // at the active area. Sensors without a colour filter array have nothing to white
+        // balance. Manual white balance also decides the interpolation of the calibration below,
+        // through the neutral of its gains.
+        let color_gains = match (parameters.white_balance, self.estimated_gains) {
+            _ if cfa_pattern.is_monochrome() => [1.0; 4],
+            (WhiteBalance::Auto(_), Some(estimated_gains)) => estimated_gains,
+            (WhiteBalance::Manual { temperature, tint }, _) => {
+                let white = color::temperature_to_xy(temperature as f64, tint as f64);
+                color::neutral_to_gains(ColorProfile::new(parameters).xy_to_neutral(white))
+            }
+            _ => parameters.color_gains,
+        };
+        // Gains that are missing or not positive, as when automatic white balance has no
+        // statistics to go on and the capture result no gains, fall back to daylight
+        let color_gains = if color::gains_to_neutral(color_gains).is_some() {
+            color_gains
+        } else {
+            let daylight =
+                color::neutral_to_gains(ColorProfile::new(parameters).xy_to_neutral(color::D65));
+            if color::gains_to_neutral(daylight).is_some() {
+                daylight
+            } else {
+                [1.0; 4]
+            }
+        };
+
+        let stage1 = Stage1 {
+            raw_offset: [active_area.x + crop.x, active_area.y + crop.y],
+            color_gains,
+            black_level,
+            white_level,
+            lens_shading: lens_shading.clone(),
+            lens_shading_scale,
+            cfa_pattern,
+            cfa_origin: crop.origin(),
+            extent,
+        };
+
+        // Hot and dead pixel correction, before highlight recovery mistakes hot pixels for
+        // clipped ones. Known defects are given relative to the active area.
+        let defects = parameters
+            .bad_pixels
+            .iter()
+            .map(|&[x, y]| Rect::new(x, y, 1, 1))
+            .chain(parameters.bad_rects.iter().copied())
+            .filter_map(|defect| {
+                let x = defect.x.max(crop.x);
+                let y = defect.y.max(crop.y);
+                let right = (defect.x + defect.width).min(crop.x + crop.width);
+                let bottom = (defect.y + defect.height).min(crop.y + crop.height);
+
+                (x < right && y < bottom)
+                    .then(|| Rect::new(x - crop.x, y - crop.y, right - x, bottom - y))
+            })
+            .collect::<Vec<_>>();
+
+        let correct_defects = parameters.defect_threshold.is_some() || !defects.is_empty();
+
+        let stage11 = Stage11 {
+            cfa_pattern,
+            cfa_origin: crop.origin(),
+            threshold: parameters.defect_threshold,
+            defects,
+            extent,
+        };
+
+        // Highlight recovery, before demosaicing spreads clipped values around
+        self.highlight_mask = parameters.highlight_mask.then(|| {
+            Buffer::from_iter(
+                context.memory_allocator.clone(),
+                BufferCreateInfo {
+                    usage: BufferUsage::STORAGE_BUFFER,
+                    ..Default::default()
+                },
+                AllocationCreateInfo {
+                    memory_type_filter: MemoryTypeFilter::PREFER_DEVICE
+                        | MemoryTypeFilter::HOST_RANDOM_ACCESS,
+                    ..Default::default()
+                },
+                (0..extent[0] * extent[1]).map(|_| 0u32),
+            )
+            .unwrap()
+        });
+
+        let stage10 = Stage10 {
+            color_gains,
+            lens_shading,
+            lens_shading_scale,
+            cfa_pattern,
+            cfa_origin: crop.origin(),
+            mode: parameters.highlight_mode,
+            mask: self.highlight_mask.clone(),
+            extent,
+        };
+
+        // Demosaicing of Bayer patterns, in any of the four arrangements
+        let stage2 = Stage2 {
+            first_red: cfa_pattern.first_red(crop.origin()),
+            extent,
+        };
+
+        // Demosaicing of other patterns (X-Trans), green first and then red and blue
+        let stage6 = Stage6 {
+            cfa_pattern,
+            cfa_origin: crop.origin(),
+            extent,
+        };
+        let stage7 = Stage7 {
+            cfa_pattern,
+            cfa_origin: crop.origin(),
+        };
+
+        // Color correction (sensor color space to CIE XYZ D50, adapted to the output white point
+        // and then to linear output RGB), composed into a single matrix. The calibration is
+        // interpolated for the colour temperature of the white balance neutral, monochrome
+        // sensors have none and only go through it for the exposure bias. Without any usable
+        // matrices the white balanced values are taken as linear sRGB.
+        let color_space = parameters.color_space;
+        let camera_to_output = if cfa_pattern.is_monochrome() {
+            Matrix3::IDENTITY
+        } else {
+            let neutral = color::gains_to_neutral(color_gains).unwrap_or([1.0; 3]);
+            let camera_to_pcs = ColorProfile::new(parameters)
+                .camera_to_pcs(neutral)
+                .unwrap_or_else(|| {
+                    ChromaticAdaptation::Bradford.matrix(color::D65, color::D50)
+                        * ColorSpace::Srgb.to_xyz()
+                });
+
+            color_space.pcs_to_rgb(parameters.chromatic_adaptation) * camera_to_pcs
+        };
+
+        // Exposure bias, folded into the colour correction. Automatic exposure measures the
+        // luminance on the normalized raw, in a submission of its own since the bias it gives
+        // is needed to set up the remaining stages.
+        let exposure_bias = match parameters.exposure {
+            Exposure::Manual(bias) => {
+                self.estimated_exposure = None;
+                bias
+            }
+            Exposure::Auto { percentile, target } => {
+                // Sensors without a colour filter array are expanded to grey, see Stage8
+                let luminance = if cfa_pattern.is_monochrome() {
+                    [0.0, 1.0, 0.0]
+                } else {
+                    (color_space.to_xyz() * camera_to_output).0[1].map(|n| n as f32)
+                };
+
+                let stage20 = Stage20 {
+                    luminance,
+                    cfa_pattern,
+                    cfa_origin: crop.origin(),
+                };
+
+                let stage_output = run_stages(
+                    context,
+                    &[&stage1, &stage20],
+                    StageOutput {
+                        image_views: vec![raw_image_view.clone()],
+                        ..Default::default()
+                    },
+                    work_groups,
+                );
+
+                let histogram = stage_output.buffers[0].clone().reinterpret::<[u32]>();
+
+                self.estimated_exposure =
+                    tone::auto_exposure(&histogram.read().unwrap(), percentile, target);
+                self.estimated_exposure.unwrap_or(0.0)
+            }
+        };
+
+        let stage3 = Stage3 {
+            camera_to_output: camera_to_output
+                .scale((exposure_bias as f64).exp2())
+                .to_row_major(),
+        };
+
+        // Gamut compression, right after the conversion that leaves colours out of gamut
+        let stage23 = parameters
+            .gamut_compression
+            .map(|gamut_compression| Stage23 { gamut_compression });
+
+        // Gamma correction
+        let stage4 = Stage4 {
+            transfer_function: parameters
+                .transfer_function
+                .unwrap_or(color_space.transfer_function()),
+            tone_curve: parameters.tone_curve.clone(),
+        };
+
+        // Grayscale expansion, replaces demosaicing on sensors without a colour filter array
+        let stage8 = Stage8 { extent };
+
+        // Lens distortion correction, on linear colour before encoding
+        let stage12 = parameters.lens_distortion.map(|distortion| {
+            let distortion = distortion.translate([-(crop.x as f32), -(crop.y as f32)]);
+
+            Stage12 {
+                distortion,
+                zoom: distortion.zoom(crop.size(), parameters.distortion_crop),
+                resampling: parameters.resampling,
+                extent,
+            }
+        });
+
+        // Local tone mapping by exposure fusion, down to a coarsest level of about 16 pixels
+        let stage16 = parameters.local_tone_mapping.map(|compression| {
+            let depth = Pyramid::depth(extent, 16);
+            let exposures = Pyramid::new(context, extent, depth);
+            let fused = Pyramid::new(context, extent, depth);
+
+            let passes = [
+                exposures.build(context),
+                create_exposure_fusion_passes(context, &exposures, &fused),
+                fused.collapse(context),
+            ]
+            .into_iter()
+            .flatten()
+            .collect();
+
+            Stage16 {
+                compression,
+                luminance: color_space.to_xyz().0[1].map(|n| n as f32),
+                exposures,
+                fused,
+                passes,
+                extent,
+            }
+        });
+
+        // Tone mapping, on linear colour before encoding
+        let stage15 = parameters.tone_mapping.map(|tone_mapping| {
+            let (aces_input, aces_output) = tone::aces_matrices(&color_space);
+
+            Stage15 {
+                tone_mapping,
+                aces_input: aces_input.to_row_major(),
+                aces_output: aces_output.to_row_major(),
+            }
+        });
+
+        // Creative adjustments, on linear colour after tone mapping
+        let stage22 = parameters.color_adjustment.map(|adjustment| {
+            let rgb_to_lms = adjustment::rgb_to_lms(&color_space);
+
+            Stage22 {
+                adjustment,
+                rgb_to_lms: rgb_to_lms.to_row_major(),
+                lms_to_rgb: rgb_to_lms
+                    .inverse()
+                    .expect("Output primaries are collinear")
+                    .to_row_major(),
+            }
+        });
+
+        // Looks, on linear colour after tone mapping or on the encoded colour. Tables with more
+        // entries than an image of the device holds are resampled to fit.
+        let max_lut_size = context
+            .device
+            .physical_device()
+            .properties()
+            .max_image_dimension3_d;
+        let stage21 = |look: &Look| Stage21 {
+            look: Look {
+                lut: look.lut.resample(look.lut.size.min(max_lut_size)),
+                ..*look
+            },
+        };
+        let linear_stage21 = parameters
+            .looks
+            .iter()
+            .filter(|look| look.space == LutSpace::Linear)
+            .map(stage21)
+            .collect::<Vec<_>>();
+        let encoded_stage21 = parameters
+            .looks
+            .iter()
+            .filter(|look| look.space == LutSpace::Encoded)
+            .map(stage21)
+            .collect::<Vec<_>>();
+
+        // Quantization, into the first of the formats that can hold the output the device can
+        // write from a shader. Only the first four channel formats are guaranteed to be.
+        let candidates: &[Format] = match (cfa_pattern.is_monochrome(), parameters.bit_depth) {
+            (false, BitDepth::Eight) => &[Format::R8G8B8A8_UNORM],
+            (false, BitDepth::Sixteen) => {
+                &[Format::R16G16B16A16_UNORM, Format::R16G16B16A16_SFLOAT]
+            }
+            (true, BitDepth::Eight) => &[Format::R8_UNORM, Format::R8G8B8A8_UNORM],
+            (true, BitDepth::Sixteen) => &[
+                Format::R16_UNORM,
+                Format::R16G16B16A16_UNORM,
+                Format::R16G16B16A16_SFLOAT,
+            ],
+        };
+        let format = *candidates
+            .iter()
+            .find(|&&format| {
+                context
+                    .device
+                    .physical_device()
+                    .format_properties(format)
+                    .unwrap()
+                    .optimal_tiling_features
+                    .intersects(FormatFeatures::STORAGE_IMAGE)
             })
+            .expect("No supported output format");
+
+        // Read back in the layout that was asked for
+        self.output_formats = Some((format, candidates[0]));
+
+        let stage5 = Stage5 { format, extent };
+
+        // Stages on the raw image, up to and including demosaicing, and then on the RGB image
+        let mut raw_stages: Vec<&dyn StageInPipeline> = if cfa_pattern.is_monochrome() {
+            vec![&stage1, &stage8]
+        } else if cfa_pattern.is_bayer() {
+            vec![&stage1, &stage10, &stage2]
+        } else {
+            vec![&stage1, &stage10, &stage6, &stage7]
+        };
+        let mut rgb_stages: Vec<&dyn StageInPipeline> = vec![&stage3, &stage4, &stage5];
+
+        if correct_defects {
+            raw_stages.insert(1, &stage11);
+        }
+        if let Some(stage23) = &stage23 {
+            rgb_stages.insert(1, stage23);
+        }
+
+        // Ahead of gamma correction and quantization, the geometry first so tone mapping sees
+        // the final image
+        if let Some(stage12) = &stage12 {
+            rgb_stages.insert(rgb_stages.len() - 2, stage12);
+        }
+        if let Some(stage16) = &stage16 {
+            rgb_stages.insert(rgb_stages.len() - 2, stage16);
+        }
+        if let Some(stage15) = &stage15 {
+            rgb_stages.insert(rgb_stages.len() - 2, stage15);
+        }
+        if let Some(stage22) = &stage22 {
+            rgb_stages.insert(rgb_stages.len() - 2, stage22);
+        }
+        for stage21 in &linear_stage21 {
+            rgb_stages.insert(rgb_stages.len() - 2, stage21);
+        }
+        for stage21 in &encoded_stage21 {
+            rgb_stages.insert(rgb_stages.len() - 1, stage21);
         }
 
-        let command_buffer = command_buffer_builder.build().unwrap();
+        let input = StageOutput {
+            image_views: vec![raw_image_view],
+            ..Default::default()
+        };
+
+        // Lateral chromatic aberration correction, right after demosaicing. Estimating it takes
+        // a submission of its own, as the statistics are needed to set up the correction.
+        let stage_output = match parameters.chromatic_aberration {
+            Some(ChromaticAberrationCorrection::Manual(aberration))
+                if !cfa_pattern.is_monochrome() =>
+            {
+                let stage14 = Stage14 {
+                    aberration: aberration.translate([-(crop.x as f32), -(crop.y as f32)]),
+                    extent,
+                };
+
+                run_stages(
+                    context,
+                    &[raw_stages, vec![&stage14], rgb_stages].concat(),
+                    input,
+                    work_groups,
+                )
+            }
+            Some(ChromaticAberrationCorrection::Auto) if !cfa_pattern.is_monochrome() => {
+                let center = [crop.width as f32 / 2.0, crop.height as f32 / 2.0];
+                let radius = center[0].hypot(center[1]);
+
+                let stage13 = Stage13 {
+                    center,
+                    radius,
+                    extent,
+                };
+
+                let stage_output = run_stages(
+                    context,
+                    &[raw_stages, vec![&stage13]].concat(),
+                    input,
+                    work_groups,
+                );
+
+                let sums = stage_output.buffers[0].clone().reinterpret::<[f32]>();
+
+                let stage14 = Stage14 {
+                    aberration: ChromaticAberration::estimate(
+                        &sums.read().unwrap(),
+                        center,
+                        radius,
+                    ),
+                    extent,
+                };
+
+                run_stages(
+                    context,
+                    &[vec![&stage14 as &dyn StageInPipeline], rgb_stages].concat(),
+                    StageOutput {
+                        image_views: stage_output.image_views,
+                        ..Default::default()
+                    },
+                    work_groups,
+                )
+            }
+            _ => run_stages(
+                context,
+                &[raw_stages, rgb_stages].concat(),
+                input,
+                work_groups,
+            ),
+        };
 
-        sync::now(context.device.clone())
-            .then_execute(context.queue.clone(), command_buffer)
+        // Copy quantized image to buffer
+        stage_output.commands[0]
+            .clone()
+            .execute(context.queue.clone())
             .unwrap()
             .then_signal_fence_and_flush()
             .unwrap()
             .wait(None)
             .unwrap();
 
-        // Copy quantized image to buffer
-        if let Some(stage_output) = stage_output {
-            stage_output.commands[0]
-                .clone()
-                .execute(context.queue.clone())
-                .unwrap()
-                .then_signal_fence_and_flush()
-                .unwrap()
-                .wait(None)
-                .unwrap();
-
-            // Subbufer containts metadata of the GPU buffer
-            self.output = stage_output.buffers.get(0).cloned()
-        }
+        // Subbufer containts metadata of the GPU buffer
+        self.output = stage_output.buffers.get(0).cloned()
     }
 
     pub fn get_buffer_output(&self) -> Option<Subbuffer<[u8]>> {
         self.output.clone()
     }
+
+    /// Quantized output of the last run, in the format matching its bit depth and channels even
+    /// when the device had to store it in another one.
+    pub fn get_output(&self) -> Option<Vec<u8>> {
+        let (stored, requested) = self.output_formats?;
+        let output = self.output.as_ref()?.read().unwrap();
+
+        Some(convert_output(&output, stored, requested))
+    }
+
+    /// Clipped colours of each pixel of the last run, if requested: bit 0 for red, 1 for green
+    /// and 2 for blue, row-major over the output.
+    pub fn get_highlight_mask(&self) -> Option<Subbuffer<[u32]>> {
+        self.highlight_mask.clone()
+    }
+
+    /// Gains estimated by auto white balance in the last run, if it was requested.
+    pub fn get_estimated_gains(&self) -> Option<[f32; 4]> {
+        self.estimated_gains
+    }
+
+    /// Exposure bias in EV picked by automatic exposure in the last run, if it was requested.
+    pub fn get_estimated_exposure(&self) -> Option<f32> {
+        self.estimated_exposure
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn half_floats_to_unorm16() {
+        assert_eq!(half_to_unorm16(0x0000), 0);
+        assert_eq!(half_to_unorm16(0x3c00), 65535);
+        assert_eq!(half_to_unorm16(0x3800), 32768);
+        // Negative, above one and infinite values clip
+        assert_eq!(half_to_unorm16(0xb800), 0);
+        assert_eq!(half_to_unorm16(0x4000), 65535);
+        assert_eq!(half_to_unorm16(0x7c00), 65535);
+    }
+
+    #[test]
+    fn output_in_the_requested_layout() {
+        let rgba8 = [10, 20, 30, 255, 40, 50, 60, 255];
+        assert_eq!(
+            convert_output(&rgba8, Format::R8G8B8A8_UNORM, Format::R8G8B8A8_UNORM),
+            rgba8,
+        );
+        assert_eq!(
+            convert_output(&rgba8, Format::R8G8B8A8_UNORM, Format::R8_UNORM),
+            [10, 40],
+        );
+
+        let half = [0x3800u16, 0x3c00, 0x0000, 0x3c00]
+            .iter()
+            .flat_map(|n| n.to_ne_bytes())
+            .collect::<Vec<_>>();
+        let unorm = [32768u16, 65535, 0, 65535]
+            .iter()
+            .flat_map(|n| n.to_ne_bytes())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            convert_output(
+                &half,
+                Format::R16G16B16A16_SFLOAT,
+                Format::R16G16B16A16_UNORM
+            ),
+            unorm,
+        );
+        assert_eq!(
+            convert_output(&half, Format::R16G16B16A16_SFLOAT, Format::R16_UNORM),
+            unorm[..2],
+        );
+    }
+
+    #[test]
+    fn black_level_from_unaligned_buffer() {
+        // A 4x2 buffer whose first two columns are masked, starting one byte into the
+        // allocation so the samples are not aligned
+        let samples: [u16; 8] = [64, 66, 900, 901, 68, 70, 902, 903];
+        let mut bytes = vec![0u8];
+        bytes.extend(samples.iter().flat_map(|sample| sample.to_ne_bytes()));
+
+        let active_area = Rect::new(2, 0, 2, 2);
+        let regions = [Rect::new(0, 0, 2, 2)];
+
+        assert_eq!(
+            estimate_black_level(&bytes[1..], 4, active_area, &regions),
+            Some([64.0, 66.0, 68.0, 70.0])
+        );
+    }
+
+    #[test]
+    fn black_level_outside_buffer() {
+        let bytes = [0u8; 10];
+        let regions = [Rect::new(0, 0, 2, 2)];
+
+        assert_eq!(
+            estimate_black_level(&bytes, 4, Rect::new(2, 0, 2, 2), &regions),
+            None
+        );
+    }
 }
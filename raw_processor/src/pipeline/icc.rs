@@ -0,0 +1,445 @@
+// ICC v4 display profiles describing the output colour spaces (ICC.1:2022), and their embedding
+// in JPEG files
+
+use std::fmt;
+
+use crate::pipeline::{
+    color::{ChromaticAdaptation, ColorSpace, D50, Matrix3, TransferFunction},
+    tone::ToneCurve,
+};
+
+/// XYZ of the profile connection space illuminant, as the specification rounds it.
+const PCS_ILLUMINANT: [f64; 3] = [0.9642, 1.0, 0.8249];
+
+/// Largest profile chunk a JPEG APP2 segment holds, after its length, identifier, and sequence
+/// number and count.
+const JPEG_CHUNK_SIZE: usize = 65535 - 2 - 14;
+
+fn s15_fixed16(value: f64) -> [u8; 4] {
+    ((value * 65536.0).round() as i32).to_be_bytes()
+}
+
+fn description(color_space: &ColorSpace) -> &'static str {
+    match color_space {
+        ColorSpace::Srgb => "sRGB",
+        ColorSpace::DisplayP3 => "Display P3",
+        ColorSpace::Rec2020 => "Rec. ITU-R BT.2020",
+        ColorSpace::AdobeRgb => "Adobe RGB (1998) compatible",
+        ColorSpace::ProPhotoRgb => "ROMM RGB",
+    }
+}
+
+// multiLocalizedUnicodeType with a single English record
+fn mluc(text: &str) -> Vec<u8> {
+    let utf16 = text
+        .encode_utf16()
+        .flat_map(|unit| unit.to_be_bytes())
+        .collect::<Vec<_>>();
+
+    let mut tag = b"mluc\0\0\0\0".to_vec();
+    tag.extend(1u32.to_be_bytes());
+    tag.extend(12u32.to_be_bytes());
+    tag.extend(b"enUS");
+    tag.extend((utf16.len() as u32).to_be_bytes());
+    tag.extend(28u32.to_be_bytes());
+    tag.extend(utf16);
+    tag
+}
+
+fn xyz(value: [f64; 3]) -> Vec<u8> {
+    let mut tag = b"XYZ \0\0\0\0".to_vec();
+    tag.extend(value.iter().flat_map(|&n| s15_fixed16(n)));
+    tag
+}
+
+// parametricCurveType of function type 3, the decoding inverse of the transfer function:
+// `(a X + b)^g` from `d` up and `c X` below it
+fn para(transfer_function: &TransferFunction) -> Vec<u8> {
+    let linear_slope = transfer_function.linear_slope as f64;
+    let linear_cutoff = transfer_function.linear_cutoff as f64;
+    let scale = transfer_function.scale as f64;
+    let offset = transfer_function.offset as f64;
+    let exponent = transfer_function.exponent as f64;
+
+    let g = 1.0 / exponent;
+    let a = 1.0 / scale;
+    let b = offset / scale;
+    let c = if linear_slope > 0.0 {
+        1.0 / linear_slope
+    } else {
+        0.0
+    };
+    let d = linear_slope * linear_cutoff;
+
+    let mut tag = b"para\0\0\0\0".to_vec();
+    tag.extend(3u16.to_be_bytes());
+    tag.extend(0u16.to_be_bytes());
+    tag.extend([g, a, b, c, d].iter().flat_map(|&n| s15_fixed16(n)));
+    tag
+}
+
+// curveType sampling the inverse of a tone curve, from encoded values back to linear ones.
+// Flat stretches of the curve decode to their darkest input.
+fn curv(curve: &[[f32; 2]]) -> Vec<u8> {
+    const ENTRIES: usize = 1024;
+
+    let decode = |encoded: f32| {
+        if encoded <= curve[0][1] {
+            return curve[0][0];
+        }
+        for pair in curve.windows(2) {
+            let [[x0, y0], [x1, y1]] = [pair[0], pair[1]];
+            if encoded <= y1 && y1 > y0 {
+                return x0 + (x1 - x0) * ((encoded - y0) / (y1 - y0)).clamp(0.0, 1.0);
+            }
+        }
+        curve[curve.len() - 1][0]
+    };
+
+    let mut tag = b"curv\0\0\0\0".to_vec();
+    tag.extend((ENTRIES as u32).to_be_bytes());
+    for index in 0..ENTRIES {
+        let linear = decode(index as f32 / (ENTRIES - 1) as f32);
+        tag.extend(((linear.clamp(0.0, 1.0) * 65535.0).round() as u16).to_be_bytes());
+    }
+    tag
+}
+
+fn sf32(matrix: &Matrix3) -> Vec<u8> {
+    let mut tag = b"sf32\0\0\0\0".to_vec();
+    tag.extend(matrix.0.iter().flatten().flat_map(|&n| s15_fixed16(n)));
+    tag
+}
+
+/// A display profile with the primaries and white point of the colour space, and the curves the
+/// output was encoded with: the given transfer function, or the tone curve of each channel that
+/// replaced it. Colorants are adapted to D50 with Bradford, as the specification recommends.
+pub fn icc_profile(
+    color_space: &ColorSpace,
+    transfer_function: &TransferFunction,
+    tone_curve: Option<&ToneCurve>,
+) -> Vec<u8> {
+    let adaptation = ChromaticAdaptation::Bradford.matrix(color_space.white(), D50);
+    let colorants = adaptation * color_space.to_xyz();
+    let column = |index: usize| [0, 1, 2].map(|row| colorants.0[row][index]);
+
+    let mut tags: Vec<(&[u8; 4], Vec<u8>)> = vec![
+        (b"desc", mluc(description(color_space))),
+        (b"cprt", mluc("No copyright, use freely")),
+        (b"wtpt", xyz(PCS_ILLUMINANT)),
+        (b"chad", sf32(&adaptation)),
+        (b"rXYZ", xyz(column(0))),
+        (b"gXYZ", xyz(column(1))),
+        (b"bXYZ", xyz(column(2))),
+    ];
+    let mut shared: Vec<(&[u8; 4], &[u8; 4])> = vec![];
+    match tone_curve {
+        Some(tone_curve) => tags.extend([
+            (b"rTRC", curv(&tone_curve.red)),
+            (b"gTRC", curv(&tone_curve.green)),
+            (b"bTRC", curv(&tone_curve.blue)),
+        ]),
+        // The three curves are the same, and share their data
+        None => {
+            tags.push((b"rTRC", para(transfer_function)));
+            shared.extend([(b"gTRC", b"rTRC"), (b"bTRC", b"rTRC")]);
+        }
+    }
+
+    // Tag data follows the header and the tag table, each element aligned to four bytes
+    let data_offset = 128 + 4 + 12 * (tags.len() + shared.len());
+    let mut entries = Vec::new();
+    let mut data = Vec::new();
+
+    for (signature, tag) in &tags {
+        entries.push((*signature, data_offset + data.len(), tag.len()));
+        data.extend(tag);
+        data.resize(data.len().next_multiple_of(4), 0);
+    }
+    for (signature, source) in shared {
+        let &(_, offset, length) = entries.iter().find(|entry| entry.0 == source).unwrap();
+        entries.push((signature, offset, length));
+    }
+
+    let mut table = (entries.len() as u32).to_be_bytes().to_vec();
+    for (signature, offset, length) in entries {
+        table.extend(signature);
+        table.extend((offset as u32).to_be_bytes());
+        table.extend((length as u32).to_be_bytes());
+    }
+
+    let size = data_offset + data.len();
+
+    let mut header = Vec::with_capacity(128);
+    header.extend((size as u32).to_be_bytes());
+    // No preferred CMM
+    header.extend([0; 4]);
+    header.extend(0x04400000u32.to_be_bytes());
+    header.extend(b"mntr");
+    header.extend(b"RGB ");
+    header.extend(b"XYZ ");
+    // Fixed, so that a colour space always gives the same profile
+    header.extend(
+        [2025u16, 1, 1, 0, 0, 0]
+            .iter()
+            .flat_map(|n| n.to_be_bytes()),
+    );
+    header.extend(b"acsp");
+    // Platform, flags, manufacturer, model and attributes
+    header.extend([0; 24]);
+    // Perceptual rendering intent
+    header.extend(0u32.to_be_bytes());
+    header.extend(PCS_ILLUMINANT.iter().flat_map(|&n| s15_fixed16(n)));
+    // Creator, profile ID (left uncomputed) and reserved bytes
+    header.extend([0; 48]);
+    debug_assert_eq!(header.len(), 128);
+
+    [header, table, data].concat()
+}
+
+/// Why a profile could not be embedded in a JPEG file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum JpegError {
+    /// Missing start of image marker
+    NotJpeg,
+    /// More than the 255 segments a profile can be split into
+    ProfileTooLarge,
+    /// A segment whose length runs past the end of the file, at its offset
+    TruncatedSegment(usize),
+}
+
+impl fmt::Display for JpegError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JpegError::NotJpeg => write!(f, "Not a JPEG file"),
+            JpegError::ProfileTooLarge => write!(f, "ICC profile too large for JPEG"),
+            JpegError::TruncatedSegment(offset) => {
+                write!(f, "Truncated JPEG segment at offset {offset}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for JpegError {}
+
+/// Inserts the profile into a baseline JPEG as `ICC_PROFILE` APP2 segments, after its JFIF and
+/// Exif segments. Profiles the encoder already embedded are replaced.
+pub fn embed_in_jpeg(jpeg: &[u8], profile: &[u8]) -> Result<Vec<u8>, JpegError> {
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return Err(JpegError::NotJpeg);
+    }
+
+    let chunks = profile.chunks(JPEG_CHUNK_SIZE).collect::<Vec<_>>();
+    if chunks.len() > 255 {
+        return Err(JpegError::ProfileTooLarge);
+    }
+
+    let mut icc_segments = Vec::new();
+    for (index, chunk) in chunks.iter().enumerate() {
+        icc_segments.extend([0xFF, 0xE2]);
+        icc_segments.extend(((2 + 14 + chunk.len()) as u16).to_be_bytes());
+        icc_segments.extend(b"ICC_PROFILE\0");
+        icc_segments.extend([index as u8 + 1, chunks.len() as u8]);
+        icc_segments.extend(*chunk);
+    }
+
+    let mut output = jpeg[..2].to_vec();
+    let mut position = 2;
+    let mut inserted = false;
+
+    // Application segments, up to the first segment of another kind
+    while position + 4 <= jpeg.len() && jpeg[position] == 0xFF {
+        let marker = jpeg[position + 1];
+        if !(0xE0..=0xEF).contains(&marker) {
+            break;
+        }
+
+        // The length counts itself
+        let length = u16::from_be_bytes([jpeg[position + 2], jpeg[position + 3]]) as usize;
+        let segment = jpeg
+            .get(position..position + 2 + length)
+            .filter(|_| length >= 2)
+            .ok_or(JpegError::TruncatedSegment(position))?;
+        position += 2 + length;
+
+        if !inserted && marker > 0xE1 {
+            output.extend(&icc_segments);
+            inserted = true;
+        }
+        if marker == 0xE2 && segment[4..].starts_with(b"ICC_PROFILE\0") {
+            continue;
+        }
+        output.extend(segment);
+    }
+
+    if !inserted {
+        output.extend(&icc_segments);
+    }
+    output.extend(&jpeg[position..]);
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    // Signature and data of each tag, after checking the header and the table
+    fn tags(profile: &[u8]) -> Vec<(&[u8], &[u8])> {
+        assert_eq!(u32_at(profile, 0) as usize, profile.len());
+        assert_eq!(&profile[36..40], b"acsp");
+        assert_eq!(
+            &profile[68..80],
+            &[0, 0, 0xF6, 0xD6, 0, 1, 0, 0, 0, 0, 0xD3, 0x2D]
+        );
+
+        let count = u32_at(profile, 128) as usize;
+        (0..count)
+            .map(|index| {
+                let entry = 132 + 12 * index;
+                let signature = &profile[entry..entry + 4];
+                let offset = u32_at(profile, entry + 4) as usize;
+                let length = u32_at(profile, entry + 8) as usize;
+
+                assert_eq!(offset % 4, 0);
+                assert!(offset >= 132 + 12 * count && offset + length <= profile.len());
+                (signature, &profile[offset..offset + length])
+            })
+            .collect()
+    }
+
+    fn tag<'a>(tags: &[(&[u8], &'a [u8])], signature: &[u8; 4]) -> &'a [u8] {
+        tags.iter().find(|tag| tag.0 == signature).unwrap().1
+    }
+
+    #[test]
+    fn profile_tag_table() {
+        for color_space in [
+            ColorSpace::Srgb,
+            ColorSpace::DisplayP3,
+            ColorSpace::ProPhotoRgb,
+        ] {
+            let profile = icc_profile(&color_space, &color_space.transfer_function(), None);
+            let tags = tags(&profile);
+
+            let types = [
+                (b"desc", b"mluc"),
+                (b"cprt", b"mluc"),
+                (b"wtpt", b"XYZ "),
+                (b"chad", b"sf32"),
+                (b"rXYZ", b"XYZ "),
+                (b"gXYZ", b"XYZ "),
+                (b"bXYZ", b"XYZ "),
+                (b"rTRC", b"para"),
+                (b"gTRC", b"para"),
+                (b"bTRC", b"para"),
+            ];
+            assert_eq!(tags.len(), types.len());
+            for (signature, kind) in types {
+                assert_eq!(&tag(&tags, signature)[..4], kind);
+            }
+        }
+    }
+
+    #[test]
+    fn profile_of_replaced_transfer_function() {
+        let profile = icc_profile(&ColorSpace::DisplayP3, &TransferFunction::REC709, None);
+        let tags = tags(&profile);
+
+        for signature in [b"rTRC", b"gTRC", b"bTRC"] {
+            assert_eq!(tag(&tags, signature), para(&TransferFunction::REC709));
+        }
+        assert_ne!(
+            para(&TransferFunction::REC709),
+            para(&ColorSpace::DisplayP3.transfer_function())
+        );
+    }
+
+    #[test]
+    fn profile_of_tone_curve() {
+        let linear = vec![[0.0, 0.0], [1.0, 1.0]];
+        let tone_curve = ToneCurve::new(
+            vec![[0.0, 0.0], [0.25, 0.75], [1.0, 1.0]],
+            linear.clone(),
+            linear,
+        );
+        let profile = icc_profile(
+            &ColorSpace::Srgb,
+            &TransferFunction::SRGB,
+            Some(&tone_curve),
+        );
+        let tags = tags(&profile);
+
+        // Decoded linear values of a curveType, from encoded values evenly spaced from zero
+        let entries = |signature: &[u8; 4]| {
+            let data = tag(&tags, signature);
+            assert_eq!(&data[..4], b"curv");
+            assert_eq!(u32_at(data, 8) as usize, (data.len() - 12) / 2);
+            data[12..]
+                .chunks_exact(2)
+                .map(|entry| u16::from_be_bytes([entry[0], entry[1]]) as f32 / 65535.0)
+                .collect::<Vec<_>>()
+        };
+
+        // Encoded 0.75 decodes to 0.25 on red, to itself on green and blue
+        let red = entries(b"rTRC");
+        let at = |curve: &[f32], encoded: f32| {
+            curve[(encoded * (curve.len() - 1) as f32).round() as usize]
+        };
+        assert_eq!([red[0], red[red.len() - 1]], [0.0, 1.0]);
+        assert!((at(&red, 0.75) - 0.25).abs() < 1e-3, "{}", at(&red, 0.75));
+        for signature in [b"gTRC", b"bTRC"] {
+            let curve = entries(signature);
+            assert!((at(&curve, 0.75) - 0.75).abs() < 1e-3);
+        }
+    }
+
+    // Start of image, a JFIF segment, a profile from the encoder and the start of the frame
+    fn jpeg() -> Vec<u8> {
+        let mut jpeg = vec![0xFF, 0xD8];
+        jpeg.extend([0xFF, 0xE0, 0, 6, b'J', b'F', b'I', b'F']);
+        jpeg.extend([0xFF, 0xE2, 0, 16]);
+        jpeg.extend(b"ICC_PROFILE\0\x01\x01");
+        jpeg.extend([0xFF, 0xC0, 0, 2, 0xFF, 0xD9]);
+        jpeg
+    }
+
+    #[test]
+    fn profile_embedded_in_jpeg() {
+        let profile = icc_profile(&ColorSpace::DisplayP3, &TransferFunction::SRGB, None);
+        let output = embed_in_jpeg(&jpeg(), &profile).unwrap();
+
+        // The JFIF segment, then the profile in place of the encoder's
+        assert_eq!(&output[..10], &jpeg()[..10]);
+        let length = u16::from_be_bytes([output[12], output[13]]) as usize;
+        assert_eq!(&output[10..12], &[0xFF, 0xE2]);
+        assert_eq!(&output[14..28], b"ICC_PROFILE\0\x01\x01");
+        assert_eq!(&output[28..12 + length], &profile[..]);
+        assert_eq!(&output[12 + length..], &jpeg()[28..]);
+    }
+
+    #[test]
+    fn truncated_jpeg() {
+        let profile = icc_profile(&ColorSpace::Srgb, &TransferFunction::SRGB, None);
+
+        let mut truncated = jpeg();
+        truncated.truncate(20);
+        assert_eq!(
+            embed_in_jpeg(&truncated, &profile),
+            Err(JpegError::TruncatedSegment(10))
+        );
+
+        assert_eq!(
+            embed_in_jpeg(&[0xFF, 0xD8, 0xFF, 0xE0, 0, 1], &profile),
+            Err(JpegError::TruncatedSegment(2))
+        );
+        assert_eq!(
+            embed_in_jpeg(&[0x89, b'P'], &profile),
+            Err(JpegError::NotJpeg)
+        );
+    }
+}
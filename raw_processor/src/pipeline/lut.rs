@@ -0,0 +1,389 @@
+// Colour lookup tables in the Adobe / Resolve `.cube` format, see shaders/finishing_21.slang
+
+use std::fmt;
+
+/// Shape of a lookup table.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LutKind {
+    /// A curve per channel
+    OneDimensional,
+    /// A cube indexed by all three channels
+    ThreeDimensional,
+}
+
+/// Interpolation between the entries of a three dimensional table. One dimensional tables are
+/// always interpolated linearly.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LutInterpolation {
+    /// Between the eight corners of the cell
+    Trilinear,
+    /// Between the four corners of the tetrahedron of the cell, which keeps the neutral axis
+    /// neutral
+    Tetrahedral,
+}
+
+/// Where in the pipeline a table applies.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LutSpace {
+    /// On linear output RGB, before the transfer function
+    Linear,
+    /// On encoded output RGB, after the transfer function
+    Encoded,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lut {
+    pub kind: LutKind,
+    /// Entries along each axis
+    pub size: u32,
+    /// Red changes fastest, then green, then blue. One dimensional tables hold a curve per
+    /// channel.
+    pub table: Vec<[f32; 3]>,
+    /// Input values mapped to the first and last entries
+    pub domain_min: [f32; 3],
+    pub domain_max: [f32; 3],
+}
+
+/// Why a `.cube` file could not be read.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CubeError {
+    /// A size, domain or table entry that does not parse, with its line number from one
+    InvalidLine(usize),
+    /// More than one table size, e.g. a file with both a one and a three dimensional table
+    MultipleSizes,
+    MissingSize,
+    /// Table entries found, against those the size calls for
+    EntryCount {
+        expected: usize,
+        found: usize,
+    },
+    /// A domain whose minimum is not below its maximum
+    EmptyDomain,
+}
+
+impl fmt::Display for CubeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CubeError::InvalidLine(line) => write!(f, "Invalid .cube line {line}"),
+            CubeError::MultipleSizes => write!(f, "More than one table size in .cube file"),
+            CubeError::MissingSize => write!(f, "Missing .cube table size"),
+            CubeError::EntryCount { expected, found } => {
+                write!(f, ".cube table has {found} entries instead of {expected}")
+            }
+            CubeError::EmptyDomain => write!(f, "Empty .cube domain"),
+        }
+    }
+}
+
+impl std::error::Error for CubeError {}
+
+impl Lut {
+    /// Parses a `.cube` file. Tables with both a one and a three dimensional part are not
+    /// supported, keywords other than the size and domain (e.g. `LUT_IN_VIDEO_RANGE`) are
+    /// ignored.
+    pub fn from_cube(text: &str) -> Result<Lut, CubeError> {
+        let mut kind = None;
+        let mut domain_min = [0.0; 3];
+        let mut domain_max = [1.0; 3];
+        let mut table = Vec::new();
+
+        let parse = |value: &str| value.parse::<f32>().ok().filter(|n| n.is_finite());
+        let parse_triple = |values: &[&str]| -> Option<[f32; 3]> {
+            match *values {
+                [r, g, b] => Some([parse(r)?, parse(g)?, parse(b)?]),
+                _ => None,
+            }
+        };
+
+        for (number, line) in text.lines().enumerate() {
+            let invalid = CubeError::InvalidLine(number + 1);
+
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            let keyword = words.next().unwrap();
+            let values = words.collect::<Vec<_>>();
+
+            match keyword {
+                "LUT_1D_SIZE" | "LUT_3D_SIZE" => {
+                    if kind.is_some() {
+                        return Err(CubeError::MultipleSizes);
+                    }
+
+                    let size = values
+                        .first()
+                        .and_then(|size| size.parse::<usize>().ok())
+                        .filter(|&size| size >= 2)
+                        .ok_or(invalid)?;
+
+                    kind = Some(if keyword == "LUT_1D_SIZE" {
+                        (LutKind::OneDimensional, size)
+                    } else {
+                        (LutKind::ThreeDimensional, size)
+                    });
+                }
+                "DOMAIN_MIN" => domain_min = parse_triple(&values).ok_or(invalid)?,
+                "DOMAIN_MAX" => domain_max = parse_triple(&values).ok_or(invalid)?,
+                // Resolve's older form of the domain, the same for every channel
+                "LUT_1D_INPUT_RANGE" | "LUT_3D_INPUT_RANGE" => match *values {
+                    [min, max] => {
+                        let [min, max] = [parse(min), parse(max)];
+                        domain_min = [min.ok_or(invalid.clone())?; 3];
+                        domain_max = [max.ok_or(invalid)?; 3];
+                    }
+                    _ => return Err(invalid),
+                },
+                _ if keyword.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '.') => {
+                    let entry = line.split_whitespace().collect::<Vec<_>>();
+                    table.push(parse_triple(&entry).ok_or(invalid)?);
+                }
+                // TITLE, and the keywords of other applications
+                _ => {}
+            }
+        }
+
+        let (kind, size) = kind.ok_or(CubeError::MissingSize)?;
+
+        let expected = match kind {
+            LutKind::OneDimensional => Some(size),
+            LutKind::ThreeDimensional => size.checked_mul(size).and_then(|n| n.checked_mul(size)),
+        };
+        if expected != Some(table.len()) {
+            return Err(CubeError::EntryCount {
+                expected: expected.unwrap_or(usize::MAX),
+                found: table.len(),
+            });
+        }
+
+        if (0..3).any(|channel| domain_min[channel] >= domain_max[channel]) {
+            return Err(CubeError::EmptyDomain);
+        }
+
+        Ok(Lut {
+            kind,
+            size: size as u32,
+            table,
+            domain_min,
+            domain_max,
+        })
+    }
+
+    /// The same table with the given number of entries along each axis, interpolated linearly
+    /// from this one. Devices only guarantee 3D images of 256 texels a side, fewer than the one
+    /// dimensional tables of many applications have.
+    pub fn resample(&self, size: u32) -> Lut {
+        assert!(size >= 2, "A table needs at least two entries");
+        if size == self.size {
+            return self.clone();
+        }
+
+        // Entry below a new entry along an axis, and the weight of the one above it
+        let scale = (self.size - 1) as f64 / (size - 1) as f64;
+        let locate = |index: u32| {
+            let position = index as f64 * scale;
+            let base = (position.floor() as u32).min(self.size - 2);
+            (base, (position - base as f64) as f32)
+        };
+        let entry =
+            |[r, g, b]: [u32; 3]| self.table[((b * self.size + g) * self.size + r) as usize];
+
+        let table = match self.kind {
+            LutKind::OneDimensional => (0..size)
+                .map(|index| {
+                    let (base, t) = locate(index);
+                    let [low, high] = [self.table[base as usize], self.table[base as usize + 1]];
+                    [0, 1, 2].map(|channel| low[channel] + (high[channel] - low[channel]) * t)
+                })
+                .collect(),
+            LutKind::ThreeDimensional => (0..size * size * size)
+                .map(|index| {
+                    let [(r, tr), (g, tg), (b, tb)] =
+                        [index % size, index / size % size, index / (size * size)].map(locate);
+
+                    let mut value = [0.0; 3];
+                    for corner in 0..8 {
+                        let [dr, dg, db] = [corner & 1, (corner >> 1) & 1, corner >> 2];
+                        let weight = [(dr, tr), (dg, tg), (db, tb)]
+                            .iter()
+                            .map(|&(d, t)| if d == 1 { t } else { 1.0 - t })
+                            .product::<f32>();
+                        let corner = entry([r + dr, g + dg, b + db]);
+                        for channel in 0..3 {
+                            value[channel] += weight * corner[channel];
+                        }
+                    }
+                    value
+                })
+                .collect(),
+        };
+
+        Lut {
+            size,
+            table,
+            ..*self
+        }
+    }
+
+    /// Extent of the image that holds the table, a row for one dimensional tables.
+    pub fn extent(&self) -> [u32; 3] {
+        match self.kind {
+            LutKind::OneDimensional => [self.size, 1, 1],
+            LutKind::ThreeDimensional => [self.size; 3],
+        }
+    }
+}
+
+/// A table and how to apply it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Look {
+    pub lut: Lut,
+    pub interpolation: LutInterpolation,
+    pub space: LutSpace,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_dimensional() {
+        let text = "\
+TITLE \"Curve\"
+# Comment
+LUT_1D_SIZE 3
+
+0.0 0.0 0.0
+0.25 0.5 0.75
+1.0 1.0 1.0
+";
+        let lut = Lut::from_cube(text).unwrap();
+
+        assert_eq!(lut.kind, LutKind::OneDimensional);
+        assert_eq!(lut.size, 3);
+        assert_eq!(lut.table[1], [0.25, 0.5, 0.75]);
+        assert_eq!(lut.extent(), [3, 1, 1]);
+        assert_eq!((lut.domain_min, lut.domain_max), ([0.0; 3], [1.0; 3]));
+    }
+
+    #[test]
+    fn three_dimensional() {
+        // Identity, red fastest
+        let mut text = String::from("LUT_3D_SIZE 2\nLUT_IN_VIDEO_RANGE\n");
+        for index in 0..8 {
+            let [r, g, b] = [index & 1, (index >> 1) & 1, index >> 2];
+            text += &format!("{r}.0 {g}.0 {b}.0\n");
+        }
+        let lut = Lut::from_cube(&text).unwrap();
+
+        assert_eq!(lut.kind, LutKind::ThreeDimensional);
+        assert_eq!(lut.extent(), [2, 2, 2]);
+        assert_eq!(lut.table[1], [1.0, 0.0, 0.0]);
+        assert_eq!(lut.table[6], [0.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn domain() {
+        let text = "\
+LUT_1D_SIZE 2
+DOMAIN_MIN -0.125 0 0
+DOMAIN_MAX 1 2.5 4
+0 0 0
+1 1 1
+";
+        let lut = Lut::from_cube(text).unwrap();
+        assert_eq!(lut.domain_min, [-0.125, 0.0, 0.0]);
+        assert_eq!(lut.domain_max, [1.0, 2.5, 4.0]);
+
+        let text = "LUT_3D_INPUT_RANGE 0 4\nLUT_1D_SIZE 2\n0 0 0\n1 1 1\n";
+        let lut = Lut::from_cube(text).unwrap();
+        assert_eq!((lut.domain_min, lut.domain_max), ([0.0; 3], [4.0; 3]));
+
+        let text = "LUT_1D_SIZE 2\nDOMAIN_MIN 1 0 0\nDOMAIN_MAX 1 1 1\n0 0 0\n1 1 1\n";
+        assert_eq!(Lut::from_cube(text), Err(CubeError::EmptyDomain));
+    }
+
+    #[test]
+    fn missing_entries() {
+        let text = "LUT_3D_SIZE 2\n0 0 0\n1 0 0\n";
+        assert_eq!(
+            Lut::from_cube(text),
+            Err(CubeError::EntryCount {
+                expected: 8,
+                found: 2
+            })
+        );
+
+        assert_eq!(Lut::from_cube("0 0 0\n"), Err(CubeError::MissingSize));
+    }
+
+    #[test]
+    fn invalid_lines() {
+        let text = "LUT_1D_SIZE 2\n0 0 0\n1 one 1\n";
+        assert_eq!(Lut::from_cube(text), Err(CubeError::InvalidLine(3)));
+
+        let text = "LUT_1D_SIZE 2\n0 0\n1 1 1\n";
+        assert_eq!(Lut::from_cube(text), Err(CubeError::InvalidLine(2)));
+
+        assert_eq!(
+            Lut::from_cube("LUT_3D_SIZE 1\n0 0 0\n"),
+            Err(CubeError::InvalidLine(1))
+        );
+        assert_eq!(
+            Lut::from_cube("LUT_1D_SIZE 2\nLUT_3D_SIZE 2\n"),
+            Err(CubeError::MultipleSizes)
+        );
+    }
+
+    #[test]
+    fn large_one_dimensional() {
+        // As Resolve exports them, far longer than a 3D image holds
+        let size = 65536;
+        let mut text = format!("LUT_1D_SIZE {size}\n");
+        for index in 0..size {
+            let x = index as f32 / (size - 1) as f32;
+            text += &format!("{x} {} {}\n", x * x, x.sqrt());
+        }
+        let lut = Lut::from_cube(&text).unwrap();
+        assert_eq!(lut.extent(), [size, 1, 1]);
+
+        // Every 257th entry
+        let resampled = lut.resample(256);
+        assert_eq!(resampled.extent(), [256, 1, 1]);
+        for (index, &[r, g, b]) in resampled.table.iter().enumerate() {
+            let x = index as f32 / 255.0;
+            assert!((r - x).abs() < 1e-5, "{index} {r}");
+            assert!((g - x * x).abs() < 1e-5, "{index} {g}");
+            assert!((b - x.sqrt()).abs() < 1e-5, "{index} {b}");
+        }
+    }
+
+    #[test]
+    fn resampled_three_dimensional() {
+        // A linear map is kept exactly by trilinear interpolation
+        let size = 5;
+        let mut text = format!("LUT_3D_SIZE {size}\n");
+        for index in 0..size * size * size {
+            let [r, g, b] = [index % size, index / size % size, index / (size * size)]
+                .map(|n| n as f32 / (size - 1) as f32);
+            text += &format!("{} {} {}\n", 0.5 * r + 0.25 * b, g, 1.0 - b);
+        }
+        let lut = Lut::from_cube(&text).unwrap();
+
+        // Between the original entries
+        let resampled = lut.resample(4);
+        assert_eq!(resampled.extent(), [4, 4, 4]);
+        for (index, entry) in resampled.table.iter().enumerate() {
+            let [r, g, b] = [index % 4, index / 4 % 4, index / 16].map(|n| n as f32 / 3.0);
+            let expected = [0.5 * r + 0.25 * b, g, 1.0 - b];
+            for channel in 0..3 {
+                assert!(
+                    (entry[channel] - expected[channel]).abs() < 1e-6,
+                    "{entry:?}"
+                );
+            }
+        }
+    }
+}
@@ -1,6 +1,27 @@
+mod adjustment;
+mod awb;
+mod cfa;
+mod color;
 mod context;
+mod distortion;
 mod finish;
+mod icc;
+mod lut;
+mod parameters;
+mod pyramid;
 mod stage;
+mod tone;
 
+pub use adjustment::ColorAdjustment;
+pub use awb::{AwbMethod, WhiteBalance};
+pub use cfa::CfaPattern;
+pub use color::{ChromaticAdaptation, ColorSpace, GamutCompression, TransferFunction};
 pub use context::Context;
-pub use finish::Finish;
+pub use distortion::{
+    ChromaticAberration, ChromaticAberrationCorrection, DistortionCrop, LensDistortion, Resampling,
+};
+pub use finish::{BitDepth, Finish, HighlightMode};
+pub use icc::{JpegError, embed_in_jpeg, icc_profile};
+pub use lut::{CubeError, Look, Lut, LutInterpolation, LutKind, LutSpace};
+pub use parameters::{GainMap, Parameters, Rect};
+pub use tone::{Exposure, MAX_TONE_CURVE_POINTS, ToneCurve, ToneMapping};
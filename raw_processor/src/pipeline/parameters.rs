@@ -0,0 +1,204 @@
+use crate::pipeline::{
+    adjustment::ColorAdjustment,
+    awb::WhiteBalance,
+    cfa::CfaPattern,
+    color::{ChromaticAdaptation, ColorSpace, GamutCompression, TransferFunction},
+    distortion::{ChromaticAberrationCorrection, DistortionCrop, LensDistortion, Resampling},
+    finish::{BitDepth, HighlightMode},
+    lut::Look,
+    tone::{Exposure, ToneCurve, ToneMapping},
+};
+
+/// Rectangle in pixels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn origin(&self) -> [u32; 2] {
+        [self.x, self.y]
+    }
+
+    pub fn size(&self) -> [u32; 2] {
+        [self.width, self.height]
+    }
+
+    /// Whether the other rectangle lies within this one. Rectangles reaching past `u32::MAX`,
+    /// e.g. from negative values, contain nothing and are contained in nothing.
+    pub fn contains(&self, other: &Rect) -> bool {
+        let end = |origin: u32, size: u32| origin.checked_add(size);
+
+        match (
+            end(self.x, self.width),
+            end(self.y, self.height),
+            end(other.x, other.width),
+            end(other.y, other.height),
+        ) {
+            (Some(right), Some(bottom), Some(other_right), Some(other_bottom)) => {
+                other.x >= self.x
+                    && other.y >= self.y
+                    && other_right <= right
+                    && other_bottom <= bottom
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Per channel gains on a regular grid spanning the whole active area, corners included, in the
+/// layout of `STATISTICS_LENS_SHADING_CORRECTION_MAP` (DNG `GainMap` opcodes with one plane per
+/// channel map onto it).
+#[derive(Clone, Debug, PartialEq)]
+pub struct GainMap {
+    /// Columns and rows of the grid
+    pub size: [u32; 2],
+    /// Row-major, in the order of the colour gains
+    pub gains: Vec<[f32; 4]>,
+}
+
+impl GainMap {
+    pub fn new(size: [u32; 2], gains: Vec<[f32; 4]>) -> GainMap {
+        assert_eq!(
+            gains.len(),
+            (size[0] * size[1]) as usize,
+            "Gain map size mismatch",
+        );
+
+        GainMap { size, gains }
+    }
+
+    /// A single point of unit gains, which changes nothing.
+    pub fn identity() -> GainMap {
+        GainMap::new([1, 1], vec![[1.0; 4]])
+    }
+}
+
+/// Everything the finishing pipeline needs to know about a capture.
+pub struct Parameters {
+    /// Size of the raw buffer, margins and optical black included
+    pub size: [u32; 2],
+
+    /// Pixels holding image data, relative to the raw buffer (`SENSOR_INFO_ACTIVE_ARRAY_SIZE`,
+    /// DNG `ActiveArea`). The CFA pattern and black level pattern start at its top-left corner.
+    pub active_area: Rect,
+    /// Final image, relative to the active area (DNG `DefaultCrop`)
+    pub crop: Rect,
+
+    pub cfa_pattern: CfaPattern,
+
+    /// Static levels (`SENSOR_INFO_WHITE_LEVEL`, `SENSOR_BLACK_LEVEL_PATTERN`). The black level
+    /// pattern is 2x2 in sensor layout, starting at the active area.
+    pub white_level: i32,
+    pub black_level: [f32; 4],
+    /// Levels reported for this frame (`SENSOR_DYNAMIC_WHITE_LEVEL`,
+    /// `SENSOR_DYNAMIC_BLACK_LEVEL`), in place of the static ones
+    pub dynamic_white_level: Option<i32>,
+    pub dynamic_black_level: Option<[f32; 4]>,
+    /// Masked pixels outside the active area, relative to the raw buffer
+    /// (`SENSOR_OPTICAL_BLACK_REGIONS`, DNG `MaskedAreas`)
+    pub optical_black_regions: Vec<Rect>,
+    /// Measures the black level on the optical black regions, in place of any reported one
+    pub estimate_black_level: bool,
+    /// Lens shading correction, applied with the black level
+    pub lens_shading_map: Option<GainMap>,
+    /// Known defective pixels and areas, relative to the active area (DNG `FixBadPixelsList`)
+    pub bad_pixels: Vec<[u32; 2]>,
+    pub bad_rects: Vec<Rect>,
+    /// Also finds hot and dead pixels by how far they stand out from their neighbours of the
+    /// same colour, in normalized units
+    pub defect_threshold: Option<f32>,
+    pub color_gains: [f32; 4],
+    pub white_balance: WhiteBalance,
+
+    pub highlight_mode: HighlightMode,
+    /// Keeps a mask of the clipped pixels, for diagnostics
+    pub highlight_mask: bool,
+    /// Lateral chromatic aberration, corrected after demosaicing. Left as is when missing.
+    pub chromatic_aberration: Option<ChromaticAberrationCorrection>,
+
+    /// XYZ to camera, for each calibration illuminant
+    pub color_matrix_1: [f32; 9],
+    pub color_matrix_2: [f32; 9],
+    /// Reference camera to individual camera, for each calibration illuminant
+    pub camera_calibration_1: [f32; 9],
+    pub camera_calibration_2: [f32; 9],
+    /// Gains applied in the analog domain, in camera space
+    pub analog_balance: [f32; 3],
+    /// White balanced camera to XYZ D50, for each calibration illuminant. The colour matrices are
+    /// inverted instead when missing.
+    pub forward_matrix_1: Option<[f32; 9]>,
+    pub forward_matrix_2: Option<[f32; 9]>,
+    /// EXIF `LightSource` of each calibration
+    pub calibration_illuminant_1: i32,
+    pub calibration_illuminant_2: i32,
+    /// Adapts the D50 profile connection space to the white point of the output
+    pub chromatic_adaptation: ChromaticAdaptation,
+
+    /// Geometric distortion of the lens, relative to the active area. Left as is when missing.
+    pub lens_distortion: Option<LensDistortion>,
+    pub distortion_crop: DistortionCrop,
+    pub resampling: Resampling,
+
+    /// Brings colours outside the output gamut back in while keeping their hue, which clipping
+    /// each channel shifts. Left to clipping when missing.
+    pub gamut_compression: Option<GamutCompression>,
+
+    /// Bias on top of the capture's exposure, in linear space
+    pub exposure: Exposure,
+    /// HDR+ local tone mapping, with the gain of the synthetic long exposure over the image
+    pub local_tone_mapping: Option<f32>,
+    /// Brings values above one into range ahead of the transfer function, which clips them
+    /// when missing
+    pub tone_mapping: Option<ToneMapping>,
+    /// Saturation, vibrance, contrast and levels, left as is when missing
+    pub color_adjustment: Option<ColorAdjustment>,
+    /// Creative looks, applied in order within their space
+    pub looks: Vec<Look>,
+
+    /// Primaries, white point and transfer function of the output
+    pub color_space: ColorSpace,
+    /// Replaces the transfer function of the output colour space, e.g. with the preset curve
+    /// the capture asked for
+    pub transfer_function: Option<TransferFunction>,
+    /// Replaces either transfer function, e.g. to match the look of the camera's own processing
+    pub tone_curve: Option<ToneCurve>,
+
+    pub bit_depth: BitDepth,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rect_contains() {
+        let outer = Rect::new(10, 20, 100, 50);
+
+        assert!(outer.contains(&outer));
+        assert!(outer.contains(&Rect::new(60, 30, 50, 40)));
+        assert!(!outer.contains(&Rect::new(60, 30, 51, 40)));
+        assert!(!outer.contains(&Rect::new(9, 30, 10, 10)));
+    }
+
+    #[test]
+    fn rect_contains_overflowing() {
+        let outer = Rect::new(0, 0, 4032, 3024);
+
+        // A negative jint origin, cast to u32, wraps back into range when added to the size
+        assert!(!outer.contains(&Rect::new(-8i32 as u32, 0, 16, 16)));
+        assert!(!Rect::new(u32::MAX, 0, 1, 1).contains(&Rect::new(0, 0, 1, 1)));
+    }
+}
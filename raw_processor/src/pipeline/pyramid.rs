@@ -0,0 +1,122 @@
+// Gaussian and Laplacian pyramids on the GPU, for the multi-scale stages. The images of every
+// level and the passes between them are prepared up front, the stage that owns a pyramid
+// records the passes where it needs them. See shaders/finishing_17.slang and
+// shaders/finishing_18.slang.
+
+use std::sync::Arc;
+
+use vulkano::{
+    descriptor_set::{DescriptorSet, WriteDescriptorSet},
+    format::Format,
+    image::{Image, ImageCreateInfo, ImageUsage, view::ImageView},
+    memory::allocator::AllocationCreateInfo,
+    pipeline::{ComputePipeline, Pipeline},
+};
+
+use crate::pipeline::{
+    context,
+    stage::{self, Pass},
+};
+
+pub struct Pyramid {
+    /// Finest level first, each one half the size of the one before, rounded up
+    pub levels: Vec<Arc<ImageView>>,
+}
+
+impl Pyramid {
+    /// RGBA images for the given number of levels, the finest of the given extent.
+    pub fn new(context: &context::Context, extent: [u32; 3], depth: usize) -> Pyramid {
+        let levels = (0..depth)
+            .scan(extent, |extent, _| {
+                let level_extent = *extent;
+                *extent = [extent[0].div_ceil(2), extent[1].div_ceil(2), 1];
+                Some(level_extent)
+            })
+            .map(|extent| {
+                let image = Image::new(
+                    context.memory_allocator.clone(),
+                    ImageCreateInfo {
+                        format: Format::R16G16B16A16_SFLOAT,
+                        extent,
+                        usage: ImageUsage::STORAGE,
+                        ..Default::default()
+                    },
+                    AllocationCreateInfo::default(),
+                )
+                .unwrap();
+
+                ImageView::new_default(image).unwrap()
+            })
+            .collect();
+
+        Pyramid { levels }
+    }
+
+    /// Levels down to the given size of the smaller side, the finest included.
+    pub fn depth(extent: [u32; 3], smallest: u32) -> usize {
+        let side = extent[0].min(extent[1]);
+        (side / smallest.max(1)).max(1).ilog2() as usize + 1
+    }
+
+    /// Passes that fill every coarser level from the finest one, blurring and halving each time.
+    pub fn build(&self, context: &context::Context) -> Vec<Pass> {
+        mod cs {
+            vulkano_shaders::shader! {
+                bytes: "shaders/finishing_17.spv"
+            }
+        }
+
+        let compute_pipeline =
+            stage::create_compute_pipeline(context, cs::load(context.device.clone()).unwrap());
+
+        self.levels
+            .windows(2)
+            .map(|levels| pass(context, &compute_pipeline, &levels[0], &levels[1]))
+            .collect()
+    }
+
+    /// Passes that collapse a Laplacian pyramid, coarsest first, adding every level to the
+    /// upsampled one below it. The image ends up in the finest level.
+    pub fn collapse(&self, context: &context::Context) -> Vec<Pass> {
+        mod cs {
+            vulkano_shaders::shader! {
+                bytes: "shaders/finishing_18.spv"
+            }
+        }
+
+        let compute_pipeline =
+            stage::create_compute_pipeline(context, cs::load(context.device.clone()).unwrap());
+
+        self.levels
+            .windows(2)
+            .rev()
+            .map(|levels| pass(context, &compute_pipeline, &levels[1], &levels[0]))
+            .collect()
+    }
+}
+
+// A pass from one level to another, over the extent of the level it writes
+fn pass(
+    context: &context::Context,
+    compute_pipeline: &Arc<ComputePipeline>,
+    input: &Arc<ImageView>,
+    output: &Arc<ImageView>,
+) -> Pass {
+    let layout = compute_pipeline.layout().set_layouts().get(0).unwrap();
+    let descriptor_set = DescriptorSet::new(
+        context.descriptor_set_allocator.clone(),
+        layout.clone(),
+        [
+            WriteDescriptorSet::image_view(0, input.clone()),
+            WriteDescriptorSet::image_view(1, output.clone()),
+        ],
+        [],
+    )
+    .unwrap();
+
+    Pass {
+        compute_pipeline: compute_pipeline.clone(),
+        descriptor_set,
+        work_groups: stage::work_group_count(output.image().extent(), context.work_group_size),
+    }
+}
@@ -5,7 +5,12 @@ use vulkano::{
     command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer},
     descriptor_set::DescriptorSet,
     image::view::ImageView,
-    pipeline::ComputePipeline,
+    pipeline::{
+        ComputePipeline, Pipeline, PipelineBindPoint, PipelineLayout,
+        PipelineShaderStageCreateInfo, compute::ComputePipelineCreateInfo,
+        layout::PipelineDescriptorSetLayoutCreateInfo,
+    },
+    shader::{ShaderModule, SpecializationConstant},
 };
 
 use crate::pipeline::context;
@@ -41,3 +46,125 @@ pub trait StageInPipeline {
         work_groups: [u32; 3],
     );
 }
+
+// A dispatch prepared ahead of recording, for stages that run several over resources of their
+// own, see pyramid.rs
+pub struct Pass {
+    pub compute_pipeline: Arc<ComputePipeline>,
+    pub descriptor_set: Arc<DescriptorSet>,
+    pub work_groups: [u32; 3],
+}
+
+impl Pass {
+    pub fn record(
+        &self,
+        command_buffer_builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+    ) {
+        command_buffer_builder
+            .bind_pipeline_compute(self.compute_pipeline.clone())
+            .unwrap()
+            .bind_descriptor_sets(
+                PipelineBindPoint::Compute,
+                self.compute_pipeline.layout().clone(),
+                0,
+                self.descriptor_set.clone(),
+            )
+            .unwrap();
+
+        unsafe {
+            command_buffer_builder.dispatch(self.work_groups).unwrap();
+        }
+    }
+}
+
+// Every shader declares its workgroup size through specialization constants 0 and 1, see
+// shaders/common/workgroup.slang
+pub fn create_compute_pipeline(
+    context: &context::Context,
+    compute_shader: Arc<ShaderModule>,
+) -> Arc<ComputePipeline> {
+    let [x, y] = context.work_group_size;
+    let specialization_info = [
+        (0, SpecializationConstant::U32(x)),
+        (1, SpecializationConstant::U32(y)),
+    ];
+
+    let entry_point = compute_shader
+        .specialize(specialization_info.into_iter().collect())
+        .unwrap()
+        .entry_point("main")
+        .unwrap();
+
+    let stage = PipelineShaderStageCreateInfo::new(entry_point);
+    let layout = PipelineLayout::new(
+        context.device.clone(),
+        PipelineDescriptorSetLayoutCreateInfo::from_stages([&stage])
+            .into_pipeline_layout_create_info(context.device.clone())
+            .unwrap(),
+    )
+    .unwrap();
+
+    ComputePipeline::new(
+        context.device.clone(),
+        None,
+        ComputePipelineCreateInfo::stage_layout(stage, layout),
+    )
+    .expect("Failed to create compute pipeline")
+}
+
+// Largest square workgroup up to 16x16 the device can run. 8x8 always fits within the minimum
+// limits required by Vulkan.
+pub fn work_group_size(max_size: [u32; 3], max_invocations: u32) -> [u32; 2] {
+    let mut side = 16;
+    while side > 8 && (side * side > max_invocations || side > max_size[0] || side > max_size[1]) {
+        side /= 2;
+    }
+
+    [side, side]
+}
+
+// Enough workgroups to cover the whole extent, shaders discard the threads that fall outside
+pub fn work_group_count(extent: [u32; 3], work_group_size: [u32; 2]) -> [u32; 3] {
+    [
+        extent[0].div_ceil(work_group_size[0]),
+        extent[1].div_ceil(work_group_size[1]),
+        1,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn work_group_count_covers_the_extent() {
+        // Sizes no workgroup divides, and one every workgroup does
+        for extent in [[4031, 3023, 1], [1, 1, 1], [17, 9, 1], [4032, 3024, 1]] {
+            for work_group_size in [[8, 8], [16, 16]] {
+                let [x, y, z] = work_group_count(extent, work_group_size);
+
+                assert!(x * work_group_size[0] >= extent[0]);
+                assert!(y * work_group_size[1] >= extent[1]);
+                // Never a whole workgroup more than needed
+                assert!((x - 1) * work_group_size[0] < extent[0]);
+                assert!((y - 1) * work_group_size[1] < extent[1]);
+                assert_eq!(z, 1);
+            }
+        }
+
+        assert_eq!(work_group_count([4031, 3023, 1], [16, 16]), [252, 189, 1]);
+        assert_eq!(work_group_count([4031, 3023, 1], [8, 8]), [504, 378, 1]);
+    }
+
+    #[test]
+    fn work_group_size_within_limits() {
+        // Typical desktop and mobile limits
+        assert_eq!(work_group_size([1024, 1024, 64], 1024), [16, 16]);
+        assert_eq!(work_group_size([256, 256, 64], 256), [16, 16]);
+        // Too few invocations, or too small along one axis, for 16x16
+        assert_eq!(work_group_size([1024, 1024, 64], 128), [8, 8]);
+        assert_eq!(work_group_size([128, 8, 64], 1024), [8, 8]);
+        // The minimum limits of Vulkan
+        assert_eq!(work_group_size([128, 128, 64], 128), [8, 8]);
+    }
+}
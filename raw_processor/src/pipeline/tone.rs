@@ -0,0 +1,299 @@
+// Exposure and global tone mapping of scene linear values into the display range, see
+// shaders/finishing_15.slang and shaders/finishing_20.slang, and tone curves in place of the
+// transfer function, see shaders/finishing_4.slang
+
+use crate::pipeline::color::{self, ChromaticAdaptation, ColorSpace, Matrix3};
+
+/// Bins of the luminance histogram, over `log2` of the luminance.
+pub const EXPOSURE_HISTOGRAM_BINS: usize = 256;
+/// Range of the luminance histogram, in stops.
+pub const EXPOSURE_HISTOGRAM_RANGE: [f32; 2] = [-12.0, 4.0];
+/// Largest bias automatic exposure picks, either way, in stops.
+pub const MAX_AUTO_EXPOSURE: f32 = 4.0;
+/// Control points of each channel of a tone curve, as many as Camera2 guarantees. Longer curves
+/// are resampled to this many.
+pub const MAX_TONE_CURVE_POINTS: usize = 64;
+
+/// Exposure bias applied in linear space, on top of the capture's own exposure and separate
+/// from the white balance.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Exposure {
+    /// Bias in EV, zero leaves the image as captured
+    Manual(f32),
+    /// Bias that brings the luminance below which the given fraction of the image lies (e.g.
+    /// 0.5 for the median) to the target linear luminance (e.g. 0.18)
+    Auto { percentile: f32, target: f32 },
+}
+
+/// Bias in EV that brings a percentile of the luminance histogram to the target, limited to
+/// `MAX_AUTO_EXPOSURE`. Nothing when the histogram is empty.
+pub fn auto_exposure(histogram: &[u32], percentile: f32, target: f32) -> Option<f32> {
+    let total: u64 = histogram.iter().map(|&n| n as u64).sum();
+    let wanted = (total as f64 * percentile.clamp(0.0, 1.0) as f64).ceil() as u64;
+
+    let mut accumulated = 0;
+    let bin = histogram.iter().position(|&n| {
+        accumulated += n as u64;
+        n > 0 && accumulated >= wanted
+    })?;
+
+    // Middle of the bin, in stops
+    let [low, high] = EXPOSURE_HISTOGRAM_RANGE;
+    let stops = low + (bin as f32 + 0.5) / EXPOSURE_HISTOGRAM_BINS as f32 * (high - low);
+
+    Some((target.log2() - stops).clamp(-MAX_AUTO_EXPOSURE, MAX_AUTO_EXPOSURE))
+}
+
+/// Operator applied to each channel before the transfer function, bringing values above one
+/// back into range instead of clipping them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ToneMapping {
+    /// Reinhard's operator, extended to reach one at the given white
+    ReinhardExtended { white: f32 },
+    /// Hable's filmic curve from Uncharted 2, reaching one at the given white
+    Hable { white: f32 },
+    /// Hill's fit of the ACES reference rendering and output transforms, in ACEScg with the
+    /// input and output matrices of the output colour space, see `aces_matrices`
+    AcesFitted,
+    /// Contrast around a pivot between a black and a white point, a contrast of one is linear
+    SCurve {
+        black: f32,
+        white: f32,
+        contrast: f32,
+        pivot: f32,
+    },
+}
+
+impl ToneMapping {
+    /// Operator index and parameters, as expected by the shader.
+    pub fn push_constants(&self) -> (u32, [f32; 4]) {
+        match *self {
+            ToneMapping::ReinhardExtended { white } => (0, [white, 0.0, 0.0, 0.0]),
+            ToneMapping::Hable { white } => (1, [white, 0.0, 0.0, 0.0]),
+            ToneMapping::AcesFitted => (2, [0.0; 4]),
+            ToneMapping::SCurve {
+                black,
+                white,
+                contrast,
+                pivot,
+            } => (3, [black, white, contrast, pivot]),
+        }
+    }
+}
+
+/// Chromaticities of the ACEScg (AP1) primaries and of its white, roughly D60.
+const AP1_PRIMARIES: [[f64; 2]; 3] = [[0.713, 0.293], [0.165, 0.830], [0.128, 0.044]];
+const AP1_WHITE: [f64; 2] = [0.32168, 0.33767];
+
+// Saturation of the reference rendering and output transforms, as blends towards the AP1
+// luminance
+const RRT_SATURATION: f64 = 0.96;
+const ODT_SATURATION: f64 = 0.93;
+
+fn ap1_saturation(saturation: f64) -> Matrix3 {
+    let luminance = color::rgb_to_xyz(AP1_PRIMARIES, AP1_WHITE).0[1];
+    Matrix3([0, 1, 2].map(|i| {
+        [0, 1, 2].map(|j| (1.0 - saturation) * luminance[j] + if i == j { saturation } else { 0.0 })
+    }))
+}
+
+/// Linear RGB of the colour space into the ACEScg space of `ToneMapping::AcesFitted` with the
+/// saturation of the reference rendering transform, and back with that of the output transform.
+/// For sRGB these are the matrices of Hill's fit.
+pub fn aces_matrices(color_space: &ColorSpace) -> (Matrix3, Matrix3) {
+    let ap1_to_xyz = color::rgb_to_xyz(AP1_PRIMARIES, AP1_WHITE);
+    let to_xyz = color_space.to_xyz();
+    let white = color_space.white();
+
+    let input = ap1_saturation(RRT_SATURATION)
+        * ap1_to_xyz.inverse().unwrap()
+        * ChromaticAdaptation::Bradford.matrix(white, AP1_WHITE)
+        * to_xyz;
+    let output = to_xyz.inverse().unwrap()
+        * ChromaticAdaptation::Bradford.matrix(AP1_WHITE, white)
+        * ap1_to_xyz
+        * ap1_saturation(ODT_SATURATION);
+
+    (input, output)
+}
+
+/// Piecewise linear curve per channel from linear to encoded values, as Camera2 reports with
+/// `TONEMAP_CURVE`. Points are `[input, output]` pairs in increasing input order, values outside
+/// the curve take its ends.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ToneCurve {
+    pub red: Vec<[f32; 2]>,
+    pub green: Vec<[f32; 2]>,
+    pub blue: Vec<[f32; 2]>,
+}
+
+impl ToneCurve {
+    pub fn new(red: Vec<[f32; 2]>, green: Vec<[f32; 2]>, blue: Vec<[f32; 2]>) -> ToneCurve {
+        for curve in [&red, &green, &blue] {
+            assert!(curve.len() >= 2, "A tone curve needs at least 2 points");
+            assert!(
+                curve.windows(2).all(|pair| pair[0][0] <= pair[1][0]),
+                "Tone curve inputs must increase"
+            );
+        }
+
+        ToneCurve {
+            red: resample(red),
+            green: resample(green),
+            blue: resample(blue),
+        }
+    }
+
+    /// Points of each channel, as expected by the shader, `MAX_TONE_CURVE_POINTS` apart.
+    pub fn points(&self) -> Vec<[f32; 2]> {
+        [&self.red, &self.green, &self.blue]
+            .into_iter()
+            .flat_map(|curve| {
+                let mut points = curve.clone();
+                points.resize(MAX_TONE_CURVE_POINTS, [0.0; 2]);
+                points
+            })
+            .collect()
+    }
+
+    /// Number of points of the red, green and blue curves.
+    pub fn lengths(&self) -> [u32; 3] {
+        [&self.red, &self.green, &self.blue].map(|curve| curve.len() as u32)
+    }
+}
+
+// A curve of more than `MAX_TONE_CURVE_POINTS` points as that many, evenly spaced over its
+// inputs
+fn resample(curve: Vec<[f32; 2]>) -> Vec<[f32; 2]> {
+    if curve.len() <= MAX_TONE_CURVE_POINTS {
+        return curve;
+    }
+
+    let [first, last] = [curve[0][0], curve[curve.len() - 1][0]];
+    let mut segment = 0;
+    (0..MAX_TONE_CURVE_POINTS)
+        .map(|index| {
+            let input = first + (last - first) * index as f32 / (MAX_TONE_CURVE_POINTS - 1) as f32;
+            while segment + 2 < curve.len() && curve[segment + 1][0] < input {
+                segment += 1;
+            }
+
+            let [[x0, y0], [x1, y1]] = [curve[segment], curve[segment + 1]];
+            let t = if x1 > x0 {
+                ((input - x0) / (x1 - x0)).clamp(0.0, 1.0)
+            } else {
+                1.0
+            };
+            [input, y0 + (y1 - y0) * t]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Bins are a sixteenth of a stop wide, from -12 stops
+    fn histogram(bins: &[(usize, u32)]) -> Vec<u32> {
+        let mut histogram = vec![0; EXPOSURE_HISTOGRAM_BINS];
+        for &(bin, n) in bins {
+            histogram[bin] = n;
+        }
+        histogram
+    }
+
+    #[test]
+    fn auto_exposure_of_empty_histogram() {
+        assert_eq!(auto_exposure(&histogram(&[]), 0.5, 0.18), None);
+        assert_eq!(auto_exposure(&[], 0.5, 0.18), None);
+    }
+
+    #[test]
+    fn auto_exposure_of_black_image() {
+        // Everything in the lowest bin, far more than the largest bias away from the target
+        let black = histogram(&[(0, 1000)]);
+        assert_eq!(auto_exposure(&black, 0.5, 0.18), Some(MAX_AUTO_EXPOSURE));
+    }
+
+    #[test]
+    fn auto_exposure_of_percentile() {
+        // Half the image at -5.96875 stops, the middle of bin 96, and half at -1.96875
+        let histogram = histogram(&[(96, 500), (160, 500)]);
+        let target = 2f32.powi(-4);
+
+        assert_eq!(auto_exposure(&histogram, 0.5, target), Some(1.96875));
+        assert_eq!(auto_exposure(&histogram, 0.75, target), Some(-2.03125));
+        // A percentile of zero takes the darkest bin with anything in it
+        assert_eq!(auto_exposure(&histogram, 0.0, target), Some(1.96875));
+    }
+
+    #[test]
+    fn aces_matrices_of_srgb() {
+        // Hill's, to five decimals. Their blue rows are up to 6e-3 away from the composition of
+        // the ACES matrices, the rest to within 1e-3
+        let hill = (
+            [
+                [0.59719, 0.35458, 0.04823],
+                [0.07600, 0.90834, 0.01566],
+                [0.02840, 0.13383, 0.83777],
+            ],
+            [
+                [1.60475, -0.53108, -0.07367],
+                [-0.10208, 1.10813, -0.00605],
+                [-0.00327, -0.07276, 1.07602],
+            ],
+        );
+
+        let (input, output) = aces_matrices(&ColorSpace::Srgb);
+        for (matrix, expected) in [(input, hill.0), (output, hill.1)] {
+            for (i, (row, expected)) in matrix.0.iter().zip(expected).enumerate() {
+                let tolerance = if i == 2 { 1e-2 } else { 1e-3 };
+                for (value, expected) in row.iter().zip(expected) {
+                    assert!((value - expected).abs() < tolerance, "{matrix:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn aces_matrices_keep_white() {
+        for color_space in [
+            ColorSpace::Srgb,
+            ColorSpace::DisplayP3,
+            ColorSpace::Rec2020,
+            ColorSpace::ProPhotoRgb,
+        ] {
+            let (input, output) = aces_matrices(&color_space);
+            for matrix in [input, output] {
+                for value in matrix.transform([1.0; 3]) {
+                    assert!((value - 1.0).abs() < 1e-9, "{color_space:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn long_tone_curve_resampled() {
+        // A square root, more finely sampled than the shader takes
+        let curve = (0..=256)
+            .map(|n| {
+                let x = n as f32 / 256.0;
+                [x, x.sqrt()]
+            })
+            .collect::<Vec<_>>();
+        let short = vec![[0.0, 0.0], [1.0, 1.0]];
+        let tone_curve = ToneCurve::new(curve, short.clone(), short.clone());
+
+        assert_eq!(tone_curve.lengths(), [MAX_TONE_CURVE_POINTS as u32, 2, 2]);
+        assert_eq!(tone_curve.green, short);
+
+        let red = &tone_curve.red;
+        assert_eq!(red[0], [0.0, 0.0]);
+        assert_eq!(red[MAX_TONE_CURVE_POINTS - 1], [1.0, 1.0]);
+        assert!(red.windows(2).all(|pair| pair[0][0] < pair[1][0]));
+        for [x, y] in red {
+            // Within what linear interpolation between 1/256 steps loses near zero
+            assert!((y - x.sqrt()).abs() < 0.02, "{x} {y}");
+        }
+    }
+}